@@ -0,0 +1,177 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Describes a tap or target, as written by `--about --format json`.
+///
+/// The layout follows the [Meltano SDK], which orchestrators use to find out
+/// what a plugin supports and how it's configured.
+///
+/// [Meltano SDK]: https://sdk.meltano.com/en/latest/cli_commands.html
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct About {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sdk_version: Option<String>,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+    /// The JSON schema of the config.
+    #[serde(default, rename = "settings", skip_serializing_if = "Option::is_none")]
+    pub config_schema: Option<Value>,
+    /// Keys this crate doesn't know about, which are preserved.
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+impl About {
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            ..Self::default()
+        }
+    }
+
+    pub fn with_description<S: Into<String>>(mut self, description: S) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    pub fn with_version<S: Into<String>>(mut self, version: S) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+
+    pub fn with_capabilities<I: IntoIterator<Item = Capability>>(
+        mut self,
+        capabilities: I,
+    ) -> Self {
+        self.capabilities.extend(capabilities);
+        self
+    }
+
+    pub fn with_config_schema(mut self, schema: Value) -> Self {
+        self.config_schema = Some(schema);
+        self
+    }
+
+    pub fn has_capability(&self, capability: &Capability) -> bool {
+        self.capabilities.contains(capability)
+    }
+}
+
+/// A feature a tap or target supports. Capabilities this crate doesn't know
+/// about are kept as [Capability::Other].
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum Capability {
+    /// The tap accepts a catalog with `--catalog`.
+    Catalog,
+    /// The tap can be run with `--discover`.
+    Discover,
+    /// The tap accepts a state with `--state`.
+    State,
+    /// The tap accepts a catalog with the legacy `--properties` option.
+    Properties,
+    /// The plugin can be run with `--about`.
+    About,
+    /// The plugin supports inline stream maps.
+    StreamMaps,
+    /// The plugin supports BATCH messages.
+    Batch,
+    Other(String),
+}
+
+impl Capability {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Capability::Catalog => "catalog",
+            Capability::Discover => "discover",
+            Capability::State => "state",
+            Capability::Properties => "properties",
+            Capability::About => "about",
+            Capability::StreamMaps => "stream-maps",
+            Capability::Batch => "batch",
+            Capability::Other(other) => other,
+        }
+    }
+}
+
+impl From<String> for Capability {
+    fn from(capability: String) -> Self {
+        match capability.as_str() {
+            "catalog" => Capability::Catalog,
+            "discover" => Capability::Discover,
+            "state" => Capability::State,
+            "properties" => Capability::Properties,
+            "about" => Capability::About,
+            "stream-maps" => Capability::StreamMaps,
+            "batch" => Capability::Batch,
+            _ => Capability::Other(capability),
+        }
+    }
+}
+
+impl From<Capability> for String {
+    fn from(capability: Capability) -> Self {
+        match capability {
+            Capability::Other(other) => other,
+            capability => capability.as_str().to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test_about {
+    use super::*;
+
+    #[test]
+    fn it_parses_sdk_output() {
+        let about: About = serde_json::from_value(serde_json::json!({
+            "name": "tap-github",
+            "description": "GitHub tap class.",
+            "version": "1.2.0",
+            "sdk_version": "0.34.1",
+            "supported_python_versions": ["3.8", "3.9"],
+            "capabilities": ["catalog", "state", "discover", "about", "stream-maps", "schema-flattening"],
+            "settings": {
+                "type": "object",
+                "properties": { "auth_token": { "type": "string" } },
+                "required": ["auth_token"]
+            }
+        }))
+        .unwrap();
+
+        assert_eq!(about.name, "tap-github");
+        assert!(about.has_capability(&Capability::StreamMaps));
+        assert!(!about.has_capability(&Capability::Batch));
+        assert_eq!(
+            about.capabilities.last(),
+            Some(&Capability::Other(String::from("schema-flattening")))
+        );
+        assert_eq!(about.config_schema.unwrap()["required"][0], "auth_token");
+
+        let value = serde_json::to_value(About::new("target-csv").with_capabilities(vec![
+            Capability::About,
+            Capability::Other(String::from("validate-records")),
+        ]))
+        .unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "name": "target-csv",
+                "capabilities": ["about", "validate-records"]
+            })
+        );
+    }
+}
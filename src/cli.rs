@@ -0,0 +1,506 @@
+use std::{
+    ffi::OsString,
+    io::{Read, Write},
+    path::PathBuf,
+    process::ExitCode,
+};
+
+use serde::de::DeserializeOwned;
+
+use crate::{
+    check_config, read_json,
+    tap::{Context, MessageWriter, Tap},
+    target::{self, JSONSchema, Target},
+    About, Error, Result,
+};
+
+/// The options passed to a tap or target on the command line.
+///
+/// See Singer's [spec](https://github.com/singer-io/getting-started/blob/master/docs/SPEC.md#synopsis)
+/// for more information.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Args {
+    pub config: Option<PathBuf>,
+    pub discover: bool,
+    pub catalog: Option<PathBuf>,
+    pub properties: Option<PathBuf>,
+    pub state: Option<PathBuf>,
+    pub about: bool,
+    /// The format of the `--about` output. Only `json` is supported, which is
+    /// also used when no format is passed.
+    pub format: Option<String>,
+}
+
+impl Args {
+    /// Parses the arguments the current process was started with.
+    pub fn from_env() -> Result<Self> {
+        Self::parse(std::env::args_os().skip(1))
+    }
+
+    /// Parses the arguments, which shouldn't include the program name.
+    /// Options that take a value accept it either as the next argument or
+    /// after an `=`, e.g. `--config config.json` or `--config=config.json`.
+    pub fn parse<I, S>(args: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        let mut parsed = Self::default();
+        let mut args = args.into_iter().map(Into::into);
+
+        while let Some(arg) = args.next() {
+            let arg = arg
+                .into_string()
+                .map_err(|arg| Error::UsageError(format!("invalid argument {:?}", arg)))?;
+
+            let (name, inline_value) = match arg.find('=') {
+                Some(index) if arg.starts_with("--") => {
+                    (&arg[..index], Some(OsString::from(&arg[index + 1..])))
+                }
+                _ => (arg.as_str(), None),
+            };
+
+            let mut value = || {
+                inline_value
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| Error::UsageError(format!("{} requires a value", name)))
+            };
+
+            match name {
+                "-c" | "--config" => parsed.config = Some(value()?.into()),
+                "--catalog" => parsed.catalog = Some(value()?.into()),
+                "-p" | "--properties" => parsed.properties = Some(value()?.into()),
+                "-s" | "--state" => parsed.state = Some(value()?.into()),
+                "-d" | "--discover" => parsed.discover = true,
+                "--about" => parsed.about = true,
+                "--format" => {
+                    let format = value()?.into_string().map_err(|format| {
+                        Error::UsageError(format!("invalid format {:?}", format))
+                    })?;
+                    parsed.format = Some(format);
+                }
+                _ => return Err(Error::UsageError(format!("unknown argument {}", arg))),
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+/// Runs the tap as a command line program, meant to be returned from `main`:
+///
+/// ```no_run
+/// # struct MyTap;
+/// # impl singer::tap::Tap for MyTap {
+/// #     type Config = serde_json::Value;
+/// #     fn discover(&self, _: &mut singer::tap::Context) -> singer::Result<singer::tap::Catalog> { unimplemented!() }
+/// #     fn sync<W: std::io::Write>(&mut self, _: &mut singer::tap::Context, _: &mut singer::tap::MessageWriter<W>) -> singer::Result<()> { unimplemented!() }
+/// # }
+/// fn main() -> std::process::ExitCode {
+///     singer::cli::run_tap(MyTap)
+/// }
+/// ```
+///
+/// The arguments are parsed with [Args::from_env] and the tap is run with
+/// [execute_tap], writing to stdout. Errors are written to stderr and turned
+/// into a failing exit code, see [exit_code].
+pub fn run_tap<T: Tap>(tap: T) -> ExitCode {
+    report(Args::from_env().and_then(|args| execute_tap(tap, &args, std::io::stdout())))
+}
+
+/// Loads the files passed in the arguments into a [Context], checking the
+/// config against the [Tap::config_schema], and runs the tap
+/// in discovery mode when `--discover` is passed, writing the catalog to `out`
+/// as JSON. Otherwise the tap is run in sync mode, writing its messages to
+/// `out`. With `--about` only the tap's [Tap::about] is written, which doesn't
+/// require a config.
+pub fn execute_tap<T: Tap, W: Write>(mut tap: T, args: &Args, mut out: W) -> Result<()> {
+    if args.about {
+        return write_about(T::about(), "tap", args, out);
+    }
+
+    let config = args
+        .config
+        .as_ref()
+        .ok_or_else(|| Error::UsageError(String::from("--config is required")))?;
+
+    let mut context = match T::config_schema() {
+        Some(schema) => Context::<T::Config>::new()
+            .load_config_with_schema(config, &JSONSchema::new(schema)?)?,
+        None => Context::<T::Config>::new().load_config(config)?,
+    };
+
+    if let Some(catalog) = &args.catalog {
+        context = context.load_catalog(catalog)?;
+    }
+
+    if let Some(properties) = &args.properties {
+        context = context.load_properties(properties)?;
+    }
+
+    if let Some(state) = &args.state {
+        context = context.load_state(state)?;
+    }
+
+    if args.discover {
+        let catalog = tap.discover(&mut context)?;
+        serde_json::to_writer_pretty(&mut out, &catalog)?;
+        writeln!(out)?;
+        out.flush()?;
+    } else {
+        let mut writer = MessageWriter::new(out);
+        tap.sync(&mut context, &mut writer)?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Runs the target as a command line program, meant to be returned from
+/// `main`. The target is created by `init` from its config:
+///
+/// ```no_run
+/// # struct MyTarget;
+/// # impl singer::target::Target for MyTarget {
+/// #     fn process_record(&mut self, _: singer::Record) -> singer::Result<()> { Ok(()) }
+/// # }
+/// # impl MyTarget {
+/// #     fn new(_: serde_json::Value) -> Self { MyTarget }
+/// # }
+/// fn main() -> std::process::ExitCode {
+///     singer::cli::run_target(|config| Ok(MyTarget::new(config)))
+/// }
+/// ```
+///
+/// The arguments are parsed with [Args::from_env] and the target is run with
+/// [execute_target], reading messages from stdin and writing the state to
+/// stdout. Errors are written to stderr and turned into a failing exit code,
+/// see [exit_code].
+pub fn run_target<T, C, F>(init: F) -> ExitCode
+where
+    T: Target,
+    C: DeserializeOwned,
+    F: FnOnce(C) -> Result<T>,
+{
+    report(Args::from_env().and_then(|args| {
+        let stdin = std::io::stdin();
+        execute_target(init, &args, stdin.lock(), std::io::stdout())
+    }))
+}
+
+/// Loads the config passed in the arguments, checks it against the
+/// [Target::config_schema], creates the target from it with `init` and
+/// processes the messages read from `input`.
+///
+/// When the target has finished, the last state it confirmed is written to
+/// `out` as a line of JSON, which orchestrators use as the `--state` of the
+/// tap's next run. The state is also written when the target fails, so that
+/// the next run resumes from it rather than starting over. With `--about` only
+/// the target's [Target::about] is written.
+pub fn execute_target<T, C, F, R, W>(init: F, args: &Args, input: R, mut out: W) -> Result<()>
+where
+    T: Target,
+    C: DeserializeOwned,
+    F: FnOnce(C) -> Result<T>,
+    R: Read,
+    W: Write,
+{
+    if args.about {
+        return write_about(T::about(), "target", args, out);
+    }
+
+    let config = args
+        .config
+        .as_ref()
+        .ok_or_else(|| Error::UsageError(String::from("--config is required")))?;
+
+    let config = read_json(config)?;
+
+    if let Some(schema) = T::config_schema() {
+        check_config(&JSONSchema::new(schema)?, &config)?;
+    }
+
+    let mut target = init(serde_json::from_value(config)?)?;
+    let mut context = target::Context::default();
+
+    let result = target.process_reader(&mut context, input);
+
+    if let Some(state) = context.state {
+        serde_json::to_writer(&mut out, state.value())?;
+        writeln!(out)?;
+        out.flush()?;
+    }
+
+    result
+}
+
+/// Writes the about of a tap or target for `--about`, failing with a usage
+/// error when it doesn't describe itself or the format isn't supported.
+fn write_about<W: Write>(about: Option<About>, kind: &str, args: &Args, mut out: W) -> Result<()> {
+    let about = about
+        .ok_or_else(|| Error::UsageError(format!("--about is not supported by this {}", kind)))?;
+
+    match args.format.as_deref() {
+        None | Some("json") => {
+            serde_json::to_writer_pretty(&mut out, &about)?;
+            writeln!(out)?;
+            out.flush()?;
+            Ok(())
+        }
+        Some(format) => Err(Error::UsageError(format!(
+            "--format {} is not supported, use json",
+            format
+        ))),
+    }
+}
+
+/// The exit code a tap or target exits with after failing with the error.
+///
+/// - `2` for invalid arguments
+/// - the child's exit code for a failed external command
+/// - `1` for everything else
+pub fn exit_code(error: &Error) -> u8 {
+    match error {
+        Error::UsageError(_) => 2,
+        Error::CommandError(Some(code), _) if (1..=255).contains(code) => *code as u8,
+        Error::SignalError(signal, _) if (1..=127).contains(signal) => 128 + *signal as u8,
+        _ => 1,
+    }
+}
+
+/// Writes the error to stderr, using the `CRITICAL` level like singer-python,
+/// and converts the result into an exit code.
+fn report(result: Result<()>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("CRITICAL {}", err);
+            ExitCode::from(exit_code(&err))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_cli {
+    use super::*;
+    use crate::{tap::Catalog, Capability, Record};
+
+    struct NumbersTap;
+
+    #[derive(serde::Deserialize)]
+    struct NumbersConfig {
+        count: i64,
+    }
+
+    impl Tap for NumbersTap {
+        type Config = NumbersConfig;
+
+        fn about() -> Option<About> {
+            Some(
+                About::new("tap-numbers")
+                    .with_capabilities(vec![Capability::Discover, Capability::About])
+                    .with_config_schema(serde_json::json!({
+                        "type": "object",
+                        "properties": {
+                            "count": { "type": "integer" },
+                            "name": { "type": "string" }
+                        },
+                        "required": ["count", "name"]
+                    })),
+            )
+        }
+
+        fn discover(&self, _context: &mut Context<NumbersConfig>) -> Result<Catalog> {
+            Ok(Catalog { streams: vec![] })
+        }
+
+        fn sync<W: Write>(
+            &mut self,
+            context: &mut Context<NumbersConfig>,
+            writer: &mut MessageWriter<W>,
+        ) -> Result<()> {
+            for number in 0..context.config()?.count {
+                writer.write_record(Record::new("numbers", serde_json::json!({ "n": number })))?;
+            }
+
+            Ok(())
+        }
+    }
+
+    fn write_config(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}.json", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn it_parses_args() {
+        let args = Args::parse([
+            "--config",
+            "config.json",
+            "--catalog=catalog.json",
+            "-s",
+            "state.json",
+            "--discover",
+        ])
+        .unwrap();
+
+        assert_eq!(
+            args,
+            Args {
+                config: Some(PathBuf::from("config.json")),
+                catalog: Some(PathBuf::from("catalog.json")),
+                state: Some(PathBuf::from("state.json")),
+                discover: true,
+                ..Args::default()
+            }
+        );
+
+        let args = Args::parse(["--about", "--format=json"]).unwrap();
+        assert!(args.about);
+        assert_eq!(args.format.as_deref(), Some("json"));
+
+        assert!(matches!(
+            Args::parse(["--config"]),
+            Err(Error::UsageError(_))
+        ));
+        assert!(matches!(
+            Args::parse(["--verbose"]),
+            Err(Error::UsageError(_))
+        ));
+    }
+
+    #[test]
+    fn it_executes_the_tap() {
+        let config = write_config("numbers-tap-config", r#"{"count":3,"name":"numbers"}"#);
+        let args = Args::parse([OsString::from("--config"), config.clone().into()]).unwrap();
+
+        let mut out = vec![];
+        execute_tap(NumbersTap, &args, &mut out).unwrap();
+
+        let lines: Vec<&str> = std::str::from_utf8(&out).unwrap().lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[2].contains(r#""n":2"#));
+
+        let discover = Args {
+            discover: true,
+            ..args
+        };
+        let mut out = vec![];
+        execute_tap(NumbersTap, &discover, &mut out).unwrap();
+        let catalog: Catalog = serde_json::from_slice(&out).unwrap();
+        assert!(catalog.streams.is_empty());
+
+        std::fs::remove_file(config).unwrap();
+    }
+
+    #[derive(Default)]
+    struct CountingTarget {
+        records: usize,
+        limit: usize,
+    }
+
+    impl Target for CountingTarget {
+        fn process_record(&mut self, _record: Record) -> Result<()> {
+            self.records += 1;
+            if self.records > self.limit {
+                return Err(Error::OtherError("too many records"));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_executes_the_target() {
+        #[derive(serde::Deserialize)]
+        struct Config {
+            limit: usize,
+        }
+
+        let init = |config: Config| {
+            Ok(CountingTarget {
+                limit: config.limit,
+                ..CountingTarget::default()
+            })
+        };
+
+        let input = [
+            r#"{"type":"SCHEMA","stream":"numbers","schema":{"type":"object"},"key_properties":["n"]}"#,
+            r#"{"type":"RECORD","stream":"numbers","record":{"n":1}}"#,
+            r#"{"type":"STATE","value":{"bookmarks":{"numbers":{"n":1}}}}"#,
+            r#"{"type":"RECORD","stream":"numbers","record":{"n":2}}"#,
+            r#"{"type":"STATE","value":{"bookmarks":{"numbers":{"n":2}}}}"#,
+        ]
+        .join("\n");
+
+        let config = write_config("counting-target-config", r#"{"limit":5}"#);
+        let args = Args {
+            config: Some(config.clone()),
+            ..Args::default()
+        };
+
+        let mut out = vec![];
+        execute_target(init, &args, input.as_bytes(), &mut out).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&out).unwrap(),
+            "{\"bookmarks\":{\"numbers\":{\"n\":2}}}\n"
+        );
+
+        std::fs::write(&config, r#"{"limit":1}"#).unwrap();
+        let mut out = vec![];
+        let err = execute_target(init, &args, input.as_bytes(), &mut out).unwrap_err();
+        assert_eq!(exit_code(&err), 1);
+        assert_eq!(
+            std::str::from_utf8(&out).unwrap(),
+            "{\"bookmarks\":{\"numbers\":{\"n\":1}}}\n"
+        );
+
+        std::fs::remove_file(config).unwrap();
+    }
+
+    #[test]
+    fn it_requires_a_config() {
+        let err = execute_tap(NumbersTap, &Args::default(), vec![]).unwrap_err();
+        assert_eq!(exit_code(&err), 2);
+    }
+
+    #[test]
+    fn it_writes_the_about() {
+        let args = Args::parse(["--about", "--format", "json"]).unwrap();
+
+        let mut out = vec![];
+        execute_tap(NumbersTap, &args, &mut out).unwrap();
+
+        let about: About = serde_json::from_slice(&out).unwrap();
+        assert_eq!(about.name, "tap-numbers");
+        assert!(about.has_capability(&Capability::Discover));
+
+        let args = Args::parse(["--about", "--format", "markdown"]).unwrap();
+        assert!(matches!(
+            execute_tap(NumbersTap, &args, vec![]),
+            Err(Error::UsageError(_))
+        ));
+
+        let args = Args::parse(["--about"]).unwrap();
+        let err = execute_target(
+            |_: serde_json::Value| Ok(CountingTarget::default()),
+            &args,
+            std::io::empty(),
+            vec![],
+        )
+        .unwrap_err();
+        assert!(matches!(err, Error::UsageError(_)));
+    }
+
+    #[test]
+    fn it_checks_the_config() {
+        let config = write_config("numbers-tap-invalid-config", r#"{"count":"three"}"#);
+        let args = Args::parse([OsString::from("--config"), config.into()]).unwrap();
+
+        match execute_tap(NumbersTap, &args, vec![]).unwrap_err() {
+            Error::InvalidConfig(errors) => assert_eq!(errors.len(), 2),
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+}
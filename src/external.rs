@@ -1,155 +1,945 @@
 use std::{
-    io::{BufRead, BufReader},
-    process::{Command, Stdio},
+    ffi::OsString,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::Arc,
+    time::Duration,
 };
 
 use serde_json::Value;
 
 use crate::{
-    tap::{Catalog, MessageWriter, Tap},
-    Error, Result,
+    tap::{Catalog, Context, MessageWriter, Tap},
+    About, Error, Message, Result, State,
 };
 
+mod files;
+mod logs;
+mod messages;
+mod process;
+mod target;
+
+pub use logs::{LogEvent, LogHandler, LogLevel};
+pub use messages::Messages;
+pub use process::{CancellationHandle, TimeoutKind, DEFAULT_GRACE_PERIOD};
+pub use target::{ExternalTarget, TargetProcess};
+
+use files::Arguments;
+use logs::StderrReader;
+use process::{Limits, SupervisedChild};
+
+/// The directory of a virtualenv that contains its executables.
+#[cfg(windows)]
+const VENV_BIN: &str = "Scripts";
+#[cfg(not(windows))]
+const VENV_BIN: &str = "bin";
+
 /// Allows for interacting with a tap that isn't implemented in rust. Running an
 /// external tap executes the program in a child process and processes messages
 /// written to stdout.
+///
+/// The tap can be stopped while it runs, either by a timeout or through its
+/// [CancellationHandle]. A stopped tap is first asked to exit with SIGTERM and
+/// then killed once the [termination grace
+/// period](ExternalTap::termination_grace_period) has passed.
 pub struct ExternalTap {
     /// The tap to execute. The program for the tap is resolved exactly as
     /// [`std::process::Command::new`] resolves programs:
     ///
     /// > If program is not an absolute path, the PATH will be searched in an
-    /// OS-defined way.
+    /// > OS-defined way.
     ///
     /// See [command's docs] for additional information.
     ///
     /// [command's docs]: std::process::Command#method.new
+    ///
+    /// When the tap has a [venv](ExternalTap::venv) and isn't a path, it's
+    /// resolved in the venv instead.
     pub tap: String,
+    /// Arguments that are passed to the tap before the arguments for the mode
+    /// it runs in, e.g. `-m tap_github` when the tap is `python`.
+    pub args: Vec<OsString>,
+    /// Changes to the environment of the tap, applied in order after the
+    /// environment is cleared by [env_clear](ExternalTap::env_clear). A
+    /// variable without a value is removed.
+    pub env: Vec<(OsString, Option<OsString>)>,
+    /// Whether the tap starts with an empty environment rather than inheriting
+    /// the environment of this process.
+    pub env_clear: bool,
+    /// The working directory of the tap, which defaults to the working
+    /// directory of this process.
+    pub current_dir: Option<PathBuf>,
+    /// The Python virtualenv the tap is installed in. Its executables take
+    /// precedence over the PATH and `VIRTUAL_ENV` is set like activating the
+    /// venv would.
+    pub venv: Option<PathBuf>,
+    /// Called with each line the tap writes to stderr.
+    pub log_handler: Option<LogHandler>,
+    /// The maximum time the tap may run for.
+    pub timeout: Option<Duration>,
+    /// The maximum time the tap may go without writing to stdout.
+    pub idle_timeout: Option<Duration>,
+    /// The time a stopped tap is given to exit before it's killed.
+    pub termination_grace_period: Duration,
+    cancellation: CancellationHandle,
 }
 
 impl ExternalTap {
     pub fn new<S: Into<String>>(tap: S) -> Self {
-        Self { tap: tap.into() }
+        Self {
+            tap: tap.into(),
+            args: vec![],
+            env: vec![],
+            env_clear: false,
+            current_dir: None,
+            venv: None,
+            log_handler: None,
+            timeout: None,
+            idle_timeout: None,
+            termination_grace_period: DEFAULT_GRACE_PERIOD,
+            cancellation: CancellationHandle::new(),
+        }
     }
-}
 
-impl Tap for ExternalTap {
-    // fn options(&self) -> &TapOptions {
-    //     &self.options
-    // }
+    /// Adds an argument that is passed to the tap before the arguments for the
+    /// mode it runs in.
+    pub fn with_arg<S: Into<OsString>>(mut self, arg: S) -> Self {
+        self.args.push(arg.into());
+        self
+    }
 
-    // fn options_mut(&mut self) -> &mut TapOptions {
-    //     &mut self.options
-    // }
+    /// Adds arguments that are passed to the tap before the arguments for the
+    /// mode it runs in.
+    pub fn with_args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Sets an environment variable of the tap.
+    pub fn with_env<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: Into<OsString>,
+        V: Into<OsString>,
+    {
+        self.env.push((key.into(), Some(value.into())));
+        self
+    }
+
+    /// Removes an environment variable of the tap, so that it isn't inherited
+    /// from this process.
+    pub fn without_env<K: Into<OsString>>(mut self, key: K) -> Self {
+        self.env.push((key.into(), None));
+        self
+    }
+
+    /// Starts the tap with an empty environment, except for the variables that
+    /// are set with [with_env](ExternalTap::with_env).
+    pub fn with_env_clear(mut self) -> Self {
+        self.env_clear = true;
+        self
+    }
 
-    /// Calls the external tap with the discover option and deserializes it into
-    /// a serde_json::Value.
-    fn discover(&self, context: &mut crate::tap::Context) -> Result<Catalog> {
-        let config = context.get_option("config")?;
+    /// Sets the working directory of the tap.
+    pub fn with_current_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.current_dir = Some(dir.into());
+        self
+    }
+
+    /// Runs the tap from the Python virtualenv at the path.
+    pub fn with_venv<P: Into<PathBuf>>(mut self, venv: P) -> Self {
+        self.venv = Some(venv.into());
+        self
+    }
+
+    /// Sets the handler that is called with each line the tap writes to
+    /// stderr, e.g. to forward the tap's logs.
+    pub fn with_log_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&LogEvent) + Send + Sync + 'static,
+    {
+        self.log_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Stops the tap with [Error::Timeout] when it runs for longer than the
+    /// timeout.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
 
-        let child = Command::new(&self.tap)
-            .args(&["--config", config, "--discover"])
+    /// Stops the tap with [Error::Timeout] when it doesn't write anything to
+    /// stdout for longer than the timeout while its output is being waited
+    /// for. Logging to stderr doesn't count as activity, and neither does the
+    /// time the tap is blocked because its output isn't being read.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the time a stopped tap is given to exit after SIGTERM before it's
+    /// killed, which defaults to [DEFAULT_GRACE_PERIOD].
+    pub fn with_termination_grace_period(mut self, grace_period: Duration) -> Self {
+        self.termination_grace_period = grace_period;
+        self
+    }
+
+    /// Uses the handle to cancel the tap, e.g. to share one handle between
+    /// several taps.
+    pub fn with_cancellation(mut self, handle: CancellationHandle) -> Self {
+        self.cancellation = handle;
+        self
+    }
+
+    /// Returns a handle that cancels the tap when it's running, or any later
+    /// run once cancelled.
+    pub fn cancellation_handle(&self) -> CancellationHandle {
+        self.cancellation.clone()
+    }
+
+    /// Runs the tap with `--about --format json` and parses the description it
+    /// writes to stdout. This works for taps built with the Meltano SDK, other
+    /// taps usually fail with [Error::CommandError].
+    pub fn about(&self) -> Result<About> {
+        let mut args = Arguments::default();
+        args.arg("--about").arg("--format").arg("json");
+
+        Ok(serde_json::from_slice(&self.output(&args)?)?)
+    }
+
+    /// Runs the tap in sync mode and returns an iterator over the messages it
+    /// writes to stdout, allowing them to be inspected or modified before
+    /// they're written anywhere.
+    pub fn messages(&self, context: &Context) -> Result<Messages> {
+        let args = sync_args(context)?;
+        let (child, stderr) = self.spawn(&args)?;
+        Ok(Messages::new(child, stderr, args))
+    }
+
+    /// Runs the tap in sync mode like [Tap::sync], but parses each message and
+    /// passes it through `f` before writing it to the writer. Messages that
+    /// `f` maps to `None` are dropped. Malformed output fails the sync with
+    /// [Error::MalformedMessage].
+    pub fn sync_with<W, F>(
+        &self,
+        context: &Context,
+        writer: &mut MessageWriter<W>,
+        mut f: F,
+    ) -> Result<()>
+    where
+        W: std::io::Write,
+        F: FnMut(Message) -> Result<Option<Message>>,
+    {
+        let mut messages = self.messages(context)?;
+
+        for message in &mut messages {
+            if let Some(message) = f(message?)? {
+                writer.write_message(&message)?;
+            }
+        }
+
+        messages.finish()
+    }
+
+    /// Spawns the tap with the arguments and with piped stdout and stderr.
+    /// The child is supervised according to the tap's timeouts and
+    /// cancellation handle, and its stderr is read on a separate thread by the
+    /// returned [StderrReader].
+    fn spawn(&self, args: &Arguments) -> Result<(SupervisedChild, StderrReader)> {
+        let mut child = process::isolate(&mut self.command()?)
+            .args(args.iter())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .spawn()
-            .map_err(|err| Error::ExecError(err))?;
+            .map_err(Error::ExecError)?;
+
+        let stderr = child.stderr.take().expect("piped stderr should be Some");
+        let stderr = StderrReader::spawn(stderr, self.log_handler.clone());
+
+        let child = SupervisedChild::new(child, self.limits());
 
-        let output = child.wait_with_output()?;
+        Ok((child, stderr))
+    }
+
+    /// Runs the tap with the arguments until it exits and returns its stdout.
+    fn output(&self, args: &Arguments) -> Result<Vec<u8>> {
+        let (mut child, stderr) = self.spawn(args)?;
+
+        let stdout = child
+            .take(|child| child.stdout.take())
+            .expect("piped stdout should be Some");
+        let mut output = vec![];
+        let read = child.track(stdout).read_to_end(&mut output);
+
+        let status = child.wait(read)?;
+        let stderr = stderr.finish()?;
 
-        if !output.status.success() {
-            let stderr: Value = serde_json::from_slice(&output.stderr)?;
-            return Err(Error::CommandError(
-                output.status.code(),
-                serde_json::to_string(&stderr)?,
-            ));
+        if !status.success() {
+            return Err(command_error(status, stderr));
         }
 
-        let catalog = serde_json::from_slice(&output.stdout)?;
+        Ok(output)
+    }
+
+    /// Creates the command for the tap with its arguments, environment and
+    /// working directory, but without the arguments for the mode it runs in.
+    fn command(&self) -> Result<Command> {
+        let mut command = Command::new(self.program());
 
-        Ok(catalog)
+        if self.env_clear {
+            command.env_clear();
+        }
+
+        for (key, value) in &self.env {
+            match value {
+                Some(value) => command.env(key, value),
+                None => command.env_remove(key),
+            };
+        }
+
+        if let Some(venv) = &self.venv {
+            let inherited = if self.env_clear {
+                None
+            } else {
+                std::env::var_os("PATH")
+            };
+            let path = self
+                .env
+                .iter()
+                .rev()
+                .find(|(key, _)| key == "PATH")
+                .map_or(inherited, |(_, value)| value.clone());
+
+            let mut paths = vec![venv.join(VENV_BIN)];
+            paths.extend(path.iter().flat_map(std::env::split_paths));
+
+            let path = std::env::join_paths(paths)
+                .map_err(|_| Error::UsageError(format!("Invalid venv {}", venv.display())))?;
+
+            command.env("PATH", path).env("VIRTUAL_ENV", venv);
+        }
+
+        if let Some(dir) = &self.current_dir {
+            command.current_dir(dir);
+        }
+
+        command.args(&self.args);
+
+        Ok(command)
+    }
+
+    /// Resolves the program of the tap, which is looked up in the venv when
+    /// it's a name rather than a path.
+    fn program(&self) -> PathBuf {
+        match &self.venv {
+            Some(venv) if Path::new(&self.tap).components().count() == 1 => {
+                venv.join(VENV_BIN).join(&self.tap)
+            }
+            _ => PathBuf::from(&self.tap),
+        }
+    }
+
+    fn limits(&self) -> Limits {
+        Limits {
+            timeout: self.timeout,
+            idle_timeout: self.idle_timeout,
+            grace_period: self.termination_grace_period,
+            cancellation: self.cancellation.clone(),
+        }
+    }
+}
+
+impl Tap for ExternalTap {
+    type Config = Value;
+
+    // fn options(&self) -> &TapOptions {
+    //     &self.options
+    // }
+
+    // fn options_mut(&mut self) -> &mut TapOptions {
+    //     &mut self.options
+    // }
+
+    /// Calls the external tap with the discover option and parses the catalog
+    /// it writes to stdout.
+    ///
+    /// A tap that exits unsuccessfully fails with [Error::CommandError] or
+    /// [Error::SignalError] containing the end of its stderr, and output that
+    /// isn't a valid catalog fails with [Error::InvalidCatalog].
+    fn discover(&self, context: &mut Context) -> Result<Catalog> {
+        let mut args = config_args(context)?;
+        args.arg("--discover");
+
+        parse_catalog(&self.output(&args)?)
     }
 
     /// Reads the data emitted to stdout by the tap and copies that data to the
     /// message writer.
     fn sync<W: std::io::Write>(
         &mut self,
-        context: &mut crate::tap::Context,
+        context: &mut Context,
         writer: &mut MessageWriter<W>,
     ) -> Result<()> {
-        let config = context.get_option("config")?;
+        let args = sync_args(context)?;
+        let (mut child, stderr) = self.spawn(&args)?;
 
-        let mut args = vec!["--config", config];
+        let stdout = child
+            .take(|child| child.stdout.take())
+            .expect("piped stdout should be Some");
+        let copied = std::io::copy(&mut child.track(stdout), writer);
 
-        if let Ok(catalog) = context.get_option("catalog") {
-            args.extend(&["--catalog", catalog]);
-        }
+        let status = child.wait(copied)?;
+        let stderr = stderr.finish()?;
 
-        if let Ok(state) = &context.get_option("state") {
-            args.extend(&["--state", state]);
+        if !status.success() {
+            return Err(command_error(status, stderr));
         }
 
-        if let Ok(properties) = &context.get_option("properties") {
-            args.extend(&["--properties", properties]);
-        }
+        Ok(())
+    }
+}
 
-        let mut child = Command::new(&self.tap)
-            .args(&args)
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .map_err(|err| Error::ExecError(err))?;
+/// Returns the arguments that pass the config to the tap, which is required
+/// by every mode of an external tap. A config that was set without a file is
+/// written to a temporary file.
+fn config_args(context: &Context) -> Result<Arguments> {
+    let mut args = Arguments::default();
 
-        let mut stdout = child.stdout.take().expect(
-            "piped stdout should be
-Some",
-        );
-        let stderr = child.stderr.take().expect(
-            "piped stderr should
-be Some",
-        );
+    if !args.file("--config", context.config_path(), context.config().ok())? {
+        return Err(Error::OptionNotSet("config"));
+    }
 
-        std::io::copy(&mut stdout, writer)?;
+    Ok(args)
+}
 
-        let output = child.wait_with_output()?;
+/// Returns the arguments that run the tap in sync mode with the files of the
+/// context. Values that were set without a file are written to temporary
+/// files.
+fn sync_args(context: &Context) -> Result<Arguments> {
+    let mut args = config_args(context)?;
 
-        if !output.status.success() {
-            let errors = BufReader::new(stderr);
-            let last_error = errors
-                .lines()
-                .last()
-                .or_else(|| {
-                    Some(Ok(String::from(
-                        "The taps process exited with an error but didn't write any data to stderr",
-                    )))
-                })
-                .expect(
-                    "or_else should set alternative message if the last line didn't contain exist \
-                     in stderr",
-                )?;
+    // a catalog loaded with `--properties` is passed the same way, so only a
+    // catalog that wasn't loaded from either file is written
+    let catalog = context
+        .catalog()
+        .filter(|_| context.properties_path().is_none());
+    args.file("--catalog", context.catalog_path(), catalog)?;
+    args.file(
+        "--state",
+        context.state_path(),
+        context.state().map(State::value),
+    )?;
+    args.file("--properties", context.properties_path(), None::<&Value>)?;
 
-            return Err(Error::CommandError(output.status.code(), last_error));
-        }
+    Ok(args)
+}
 
-        Ok(())
+/// Waits for the child to exit after its stdout has been read. When reading
+/// the stdout failed the child is killed instead, since nothing is reading its
+/// output anymore, and the error is returned.
+fn wait<T>(mut child: Child, read: std::io::Result<T>) -> Result<ExitStatus> {
+    if let Err(err) = read {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(Error::IoError(err));
+    }
+
+    Ok(child.wait()?)
+}
+
+/// Creates the error for a child that exited unsuccessfully or was terminated
+/// by a signal.
+fn command_error(status: ExitStatus, stderr: String) -> Error {
+    let stderr = if stderr.is_empty() {
+        String::from("The process exited with an error but didn't write any data to stderr")
+    } else {
+        stderr
+    };
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+
+        if let Some(signal) = status.signal() {
+            return Error::SignalError(signal, stderr);
+        }
     }
+
+    Error::CommandError(status.code(), stderr)
+}
+
+/// Parses the catalog written by a tap in discover mode. When the output isn't
+/// a valid catalog the error points at the offending part of it, e.g.
+/// `streams[2].schema`.
+fn parse_catalog(output: &[u8]) -> Result<Catalog> {
+    let mut deserializer = serde_json::Deserializer::from_slice(output);
+
+    let catalog = serde_path_to_error::deserialize(&mut deserializer)
+        .map_err(|err| Error::InvalidCatalog(err.path().to_string(), err.into_inner()))?;
+
+    deserializer
+        .end()
+        .map_err(|err| Error::InvalidCatalog(String::from("."), err))?;
+
+    Ok(catalog)
 }
 
 #[cfg(test)]
 mod test_external {
     use super::*;
 
-    static TAP: &'static str = "/Volumes/CODE/python/env/bin/tap-github";
-    static CONFIG: &'static str = "/Volumes/CODE/python/config.json";
-    static CATALOG: &'static str = "/Volumes/CODE/python/properties.json";
+    static TAP: &str = "/Volumes/CODE/python/env/bin/tap-github";
+    static CONFIG: &str = "/Volumes/CODE/python/config.json";
+    static CATALOG: &str = "/Volumes/CODE/python/properties.json";
 
     #[test]
+    #[ignore = "requires tap-github and its config at the paths above"]
     fn it_works() {
         let mut tap = ExternalTap::new(TAP);
-        let mut context = crate::tap::Context::default();
-        context.set_option("config", CONFIG).unwrap();
-        context.set_option("properties", CATALOG).unwrap();
+        let mut context = Context::new()
+            .load_config(CONFIG)
+            .unwrap()
+            .load_properties(CATALOG)
+            .unwrap();
 
         let mut writer = MessageWriter::to_stdout();
 
         tap.sync(&mut context, &mut writer).unwrap();
     }
+
+    /// Writes an executable shell script that stands in for a tap.
+    #[cfg(unix)]
+    pub(crate) fn script(name: &str, body: &str) -> std::path::PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("singer-external-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let config = dir.join("config.json");
+        std::fs::write(&config, "{}").unwrap();
+
+        let path = dir.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        path
+    }
+
+    /// A context with the config written by [script].
+    #[cfg(unix)]
+    pub(crate) fn script_context(script: &std::path::Path) -> Context {
+        Context::new()
+            .load_config(script.with_file_name("config.json"))
+            .unwrap()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_reads_stderr_while_syncing() {
+        let script = script(
+            "tap-chatty",
+            r#"i=0
+while [ $i -lt 2000 ]; do
+  echo "INFO fetched page $i of users, the api returned a lot of data" >&2
+  i=$((i + 1))
+done
+echo '{"type":"RECORD","stream":"users","record":{"id":1}}'
+echo "CRITICAL connection reset" >&2
+exit 3"#,
+        );
+
+        let events = Arc::new(std::sync::Mutex::new(vec![]));
+        let mut tap = ExternalTap::new(script.to_str().unwrap()).with_log_handler({
+            let events = events.clone();
+            move |event: &LogEvent| events.lock().unwrap().push(event.level)
+        });
+
+        let mut writer = MessageWriter::to_buffer();
+        let err = tap
+            .sync(&mut script_context(&script), &mut writer)
+            .unwrap_err();
+
+        match err {
+            Error::CommandError(code, stderr) => {
+                assert_eq!(code, Some(3));
+                assert!(stderr.ends_with("CRITICAL connection reset"));
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 2001);
+        assert_eq!(events.last(), Some(&Some(LogLevel::Critical)));
+
+        let output = writer.into_inner().unwrap();
+        assert!(output.starts_with(br#"{"type":"RECORD""#));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_syncs_with_invalid_utf8_on_stderr() {
+        let script = script(
+            "tap-latin1",
+            r#"printf 'WARNING skipped caf\351\n' >&2
+i=0
+while [ $i -lt 2000 ]; do
+  echo "INFO fetched page $i" >&2
+  i=$((i + 1))
+done
+echo '{"type":"RECORD","stream":"users","record":{"id":1}}'"#,
+        );
+
+        let events = Arc::new(std::sync::Mutex::new(vec![]));
+        let mut tap = ExternalTap::new(script.to_str().unwrap()).with_log_handler({
+            let events = events.clone();
+            move |event: &LogEvent| events.lock().unwrap().push(event.message.clone())
+        });
+
+        let mut writer = MessageWriter::to_buffer();
+        tap.sync(&mut script_context(&script), &mut writer).unwrap();
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 2001);
+        assert_eq!(events[0], "skipped caf\u{fffd}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_parses_messages() {
+        let malformed = script(
+            "tap-messages",
+            r#"echo '{"type":"SCHEMA","stream":"users","schema":{},"key_properties":["id"]}'
+echo '{"type":"RECORD","stream":"users","record":{"id":1,"password":"hunter2"}}'
+echo
+echo 'Traceback (most recent call last):'
+echo '{"type":"RECORD","stream":"users","record":{"id":2,"password":"hunter3"}}'
+printf 'caf\351\n'
+echo 'bad'"#,
+        );
+        let tap = ExternalTap::new(malformed.to_str().unwrap());
+        let context = script_context(&malformed);
+
+        let results: Vec<Result<Message>> = tap.messages(&context).unwrap().collect();
+        assert_eq!(results.len(), 6);
+        assert!(matches!(results[2], Err(Error::MalformedMessage(4, _))));
+        assert!(matches!(results[4], Err(Error::MalformedMessage(6, _))));
+        assert!(matches!(results[5], Err(Error::MalformedMessage(7, _))));
+
+        let mut writer = MessageWriter::to_buffer();
+        let err = tap
+            .sync_with(&context, &mut writer, |message| Ok(Some(message)))
+            .unwrap_err();
+        assert!(matches!(err, Error::MalformedMessage(4, _)));
+
+        let passwords = script(
+            "tap-passwords",
+            r#"echo '{"type":"RECORD","stream":"users","record":{"id":1,"password":"hunter2"}}'"#,
+        );
+        let tap = ExternalTap::new(passwords.to_str().unwrap());
+
+        let mut writer = MessageWriter::to_buffer();
+        tap.sync_with(&script_context(&passwords), &mut writer, |mut message| {
+            if let Message::Record(record) = &mut message {
+                record.record["password"] = Value::Null;
+            }
+            Ok(Some(message))
+        })
+        .unwrap();
+
+        let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert!(output.contains(r#""password":null"#));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_times_out() {
+        use std::time::Instant;
+
+        let slow = script(
+            "tap-slow",
+            r#"echo '{"type":"RECORD","stream":"users","record":{"id":1}}'
+sleep 30 & wait"#,
+        );
+
+        let mut tap = ExternalTap::new(slow.to_str().unwrap())
+            .with_timeout(Duration::from_millis(300))
+            .with_termination_grace_period(Duration::from_millis(100));
+
+        let started = Instant::now();
+        let mut writer = MessageWriter::to_buffer();
+        let err = tap
+            .sync(&mut script_context(&slow), &mut writer)
+            .unwrap_err();
+
+        assert!(matches!(err, Error::Timeout(TimeoutKind::Overall, _)));
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        let tap = ExternalTap::new(slow.to_str().unwrap())
+            .with_idle_timeout(Duration::from_millis(300))
+            .with_termination_grace_period(Duration::from_millis(100));
+
+        let mut messages = tap.messages(&script_context(&slow)).unwrap();
+        assert!(messages.next().unwrap().is_ok());
+        assert!(messages.next().is_none());
+        assert!(matches!(
+            messages.finish(),
+            Err(Error::Timeout(TimeoutKind::Idle, _))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_waits_for_slow_consumers() {
+        let fast = script(
+            "tap-fast",
+            r#"i=0
+while [ $i -lt 2000 ]; do
+  echo "{\"type\":\"RECORD\",\"stream\":\"users\",\"record\":{\"id\":$i,\"bio\":\"a fairly long biography that fills the pipe\"}}"
+  i=$((i + 1))
+done"#,
+        );
+
+        let tap = ExternalTap::new(fast.to_str().unwrap())
+            .with_idle_timeout(Duration::from_millis(100))
+            .with_termination_grace_period(Duration::from_millis(100));
+
+        let mut messages = tap.messages(&script_context(&fast)).unwrap();
+
+        // the tap fills the pipe and blocks while the consumer is busy
+        assert!(messages.next().unwrap().is_ok());
+        std::thread::sleep(Duration::from_millis(500));
+
+        assert_eq!(messages.by_ref().filter(Result::is_ok).count(), 1999);
+        messages.finish().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_cancels_the_tap() {
+        let graceful = script(
+            "tap-graceful",
+            r#"trap 'echo "INFO saving progress" >&2; exit 0' TERM
+echo '{"type":"RECORD","stream":"users","record":{"id":1}}'
+sleep 30 & wait"#,
+        );
+
+        let events = Arc::new(std::sync::Mutex::new(vec![]));
+        let mut tap = ExternalTap::new(graceful.to_str().unwrap()).with_log_handler({
+            let events = events.clone();
+            move |event: &LogEvent| events.lock().unwrap().push(event.message.clone())
+        });
+
+        let handle = tap.cancellation_handle();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            handle.cancel();
+        });
+
+        let mut writer = MessageWriter::to_buffer();
+        let err = tap
+            .sync(&mut script_context(&graceful), &mut writer)
+            .unwrap_err();
+
+        assert!(matches!(err, Error::Cancelled));
+        assert_eq!(*events.lock().unwrap(), vec!["saving progress"]);
+
+        // once cancelled, the handle stops later runs right away
+        let err = tap
+            .sync(&mut script_context(&graceful), &mut writer)
+            .unwrap_err();
+        assert!(matches!(err, Error::Cancelled));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_configures_the_environment() {
+        let tap = script(
+            "tap-environment",
+            r#"printf '{"type":"RECORD","stream":"env","record":{"greeting":"%s","home":"%s","dir":"%s","arg":"%s","path":"%s","venv":"%s"}}\n' \
+  "$GREETING" "${HOME:-unset}" "$(pwd)" "$1" "$PATH" "$VIRTUAL_ENV""#,
+        );
+        let dir = tap.parent().unwrap().canonicalize().unwrap();
+        let context = script_context(&tap);
+
+        let first_record = |tap: ExternalTap| -> Value {
+            let mut messages = tap.messages(&context).unwrap();
+            let message = messages.next().unwrap().unwrap();
+            messages.finish().unwrap();
+
+            match message {
+                Message::Record(record) => record.record,
+                message => panic!("unexpected message {:?}", message),
+            }
+        };
+
+        let record = first_record(
+            ExternalTap::new(tap.to_str().unwrap())
+                .with_arg("--verbose")
+                .with_env("GREETING", "hello")
+                .without_env("HOME")
+                .with_current_dir(&dir),
+        );
+        assert_eq!(record["greeting"], "hello");
+        assert_eq!(record["home"], "unset");
+        assert_eq!(record["dir"], dir.to_str().unwrap());
+        assert_eq!(record["arg"], "--verbose");
+
+        // a venv resolves the tap in its bin directory
+        let venv = dir.join("venv");
+        std::fs::create_dir_all(venv.join("bin")).unwrap();
+        std::fs::copy(&tap, venv.join("bin").join("tap-venv")).unwrap();
+
+        let record = first_record(
+            ExternalTap::new("tap-venv")
+                .with_env_clear()
+                .with_env("PATH", "/usr/bin:/bin")
+                .with_venv(&venv),
+        );
+
+        let bin = venv.join("bin");
+        assert_eq!(record["path"], format!("{}:/usr/bin:/bin", bin.display()));
+        assert_eq!(record["venv"], venv.to_str().unwrap());
+        assert_eq!(record["home"], "unset");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_writes_values_to_temporary_files() {
+        let tap = script(
+            "tap-files",
+            r#"while [ $# -gt 0 ]; do
+  case "$1" in
+    --config) config="$2";;
+    --catalog) catalog="$2";;
+    --state) state="$2";;
+  esac
+  shift
+done
+printf '{"type":"RECORD","stream":"files","record":{"config":%s,"state":%s,"mode":"%s","catalog":"%s"}}\n' \
+  "$(cat "$config")" "$(cat "$state")" "$(ls -l "$config" | cut -c1-10)" "$catalog""#,
+        );
+
+        let mut state = State::new();
+        state.write_bookmark("users", "updated_at", "2020-01-01");
+
+        let context = Context::new()
+            .with_config(serde_json::json!({ "token": "secret" }))
+            .with_catalog(Catalog { streams: vec![] })
+            .with_state(state.clone());
+
+        let mut messages = ExternalTap::new(tap.to_str().unwrap())
+            .messages(&context)
+            .unwrap();
+        let record = match messages.next().unwrap().unwrap() {
+            Message::Record(record) => record.record,
+            message => panic!("unexpected message {:?}", message),
+        };
+
+        let catalog = Path::new(record["catalog"].as_str().unwrap());
+        assert!(catalog.exists());
+
+        messages.finish().unwrap();
+
+        assert_eq!(record["config"]["token"], "secret");
+        assert_eq!(&record["state"], state.value());
+        assert_eq!(record["mode"], "-rw-------");
+        assert!(!catalog.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_reports_discover_errors() {
+        let traceback = script(
+            "tap-traceback",
+            r#"echo 'Traceback (most recent call last):' >&2
+i=0
+while [ $i -lt 20 ]; do
+  echo "  File \"tap_github/client.py\", line $i, in request" >&2
+  echo "    response = self.session.send(request)" >&2
+  i=$((i + 1))
+done
+echo "KeyError: 'token'" >&2
+echo '' >&2
+echo 'During handling of the above exception, another exception occurred:' >&2
+echo '' >&2
+echo "KeyError: 'access_token'" >&2
+exit 1"#,
+        );
+        let tap = ExternalTap::new(traceback.to_str().unwrap());
+
+        match tap.discover(&mut script_context(&traceback)).unwrap_err() {
+            Error::CommandError(code, stderr) => {
+                assert_eq!(code, Some(1));
+                assert!(stderr.starts_with("Traceback (most recent call last):"));
+                assert_eq!(stderr.lines().count(), 46);
+                assert!(stderr.ends_with("KeyError: 'access_token'"));
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+
+        let killed = script("tap-killed", "kill -9 $$");
+        let tap = ExternalTap::new(killed.to_str().unwrap());
+        let err = tap.discover(&mut script_context(&killed)).unwrap_err();
+        assert!(matches!(err, Error::SignalError(9, _)));
+
+        let invalid = script(
+            "tap-invalid-catalog",
+            r#"echo '{"streams":[{"tap_stream_id":"users","stream":"users","schema":{}},'
+echo '{"tap_stream_id":"repos","schema":{}}]}'"#,
+        );
+        let tap = ExternalTap::new(invalid.to_str().unwrap());
+
+        match tap.discover(&mut script_context(&invalid)).unwrap_err() {
+            Error::InvalidCatalog(path, _) => assert_eq!(path, "streams[1]"),
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_discovers_large_catalogs() {
+        let tap = script(
+            "tap-large-catalog",
+            r#"printf '{"streams":['
+i=0
+while [ $i -lt 2000 ]; do
+  echo "INFO discovering table_$i" >&2
+  [ $i -gt 0 ] && printf ','
+  printf '{"tap_stream_id":"table_%s","stream":"table_%s","schema":{"type":"object"}}' $i $i
+  i=$((i + 1))
+done
+echo ']}'"#,
+        );
+
+        let catalog = ExternalTap::new(tap.to_str().unwrap())
+            .discover(&mut script_context(&tap))
+            .unwrap();
+
+        assert_eq!(catalog.streams.len(), 2000);
+        assert!(catalog.get_stream("table_1999").is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_reads_the_about() {
+        let tap = script(
+            "tap-about",
+            r#"[ "$*" = "--about --format json" ] || exit 2
+echo '{"name":"tap-about","capabilities":["catalog","discover","state"],"settings":{"type":"object"}}'"#,
+        );
+
+        let about = ExternalTap::new(tap.to_str().unwrap()).about().unwrap();
+
+        assert_eq!(about.name, "tap-about");
+        assert!(about.has_capability(&crate::Capability::Discover));
+        assert_eq!(
+            about.config_schema,
+            Some(serde_json::json!({"type": "object"}))
+        );
+    }
 }
@@ -0,0 +1,121 @@
+use std::{
+    ffi::{OsStr, OsString},
+    fs::{File, OpenOptions},
+    io::{BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use serde::Serialize;
+
+use crate::Result;
+
+/// Counts the temporary files created by this process, making their names
+/// unique.
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A JSON file in the temp directory that is only readable by the current
+/// user and removed when it's dropped. Passes values that aren't backed by a
+/// file, e.g. a config holding secrets, to an external process.
+#[derive(Debug)]
+pub(crate) struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    pub(crate) fn create<T: Serialize>(name: &str, value: &T) -> Result<Self> {
+        let (file, path) = create_new(name)?;
+        // take ownership of the path right away so the file is removed when
+        // writing it fails
+        let temp = Self { path };
+
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, value)?;
+        writer.flush()?;
+
+        Ok(temp)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Creates a file that didn't exist before, so that a file planted by someone
+/// else can't be reused.
+fn create_new(name: &str) -> Result<(File, PathBuf)> {
+    loop {
+        let path = std::env::temp_dir().join(format!(
+            "singer-{}-{}-{}.json",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed),
+            name
+        ));
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        match options.open(&path) {
+            Ok(file) => return Ok((file, path)),
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// The arguments a tap is run with, along with the temporary files that they
+/// refer to. The files are removed when the arguments are dropped, so the
+/// arguments need to outlive the tap.
+#[derive(Debug, Default)]
+pub(crate) struct Arguments {
+    args: Vec<OsString>,
+    files: Vec<TempFile>,
+}
+
+impl Arguments {
+    pub(crate) fn arg<S: Into<OsString>>(&mut self, arg: S) -> &mut Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Passes a file with the option, e.g. `--config config.json`. When there
+    /// is no path but there is a value, the value is written to a temporary
+    /// file that is passed instead. Returns whether the option was passed.
+    pub(crate) fn file<T: Serialize>(
+        &mut self,
+        option: &str,
+        path: Option<&Path>,
+        value: Option<&T>,
+    ) -> Result<bool> {
+        let path = match (path, value) {
+            (Some(path), _) => path.to_owned(),
+            (None, Some(value)) => {
+                let file = TempFile::create(option.trim_start_matches('-'), value)?;
+                let path = file.path().to_owned();
+                self.files.push(file);
+                path
+            }
+            (None, None) => return Ok(false),
+        };
+
+        self.arg(option).arg(path);
+
+        Ok(true)
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &OsStr> {
+        self.args.iter().map(OsString::as_os_str)
+    }
+}
@@ -0,0 +1,215 @@
+use std::{
+    collections::VecDeque,
+    io::{BufRead, BufReader, Read},
+    sync::Arc,
+    thread::JoinHandle,
+};
+
+use crate::{Error, Result};
+
+/// The number of bytes from the end of stderr that are kept for the message of
+/// [Error::CommandError]. It's large enough for a whole Python traceback,
+/// including chained exceptions, while bounding the memory of chatty children.
+pub(crate) const STDERR_TAIL_BYTES: usize = 64 * 1024;
+
+/// Receives the log events of an external tap or target. The handler is called
+/// from the thread that reads the child's stderr, sending the events through a
+/// [std::sync::mpsc::Sender] in the handler makes them available as a channel.
+pub type LogHandler = Arc<dyn Fn(&LogEvent) + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warning,
+    Error,
+    Critical,
+}
+
+impl LogLevel {
+    fn parse(level: &str) -> Option<Self> {
+        match level.trim().to_ascii_uppercase().as_str() {
+            "DEBUG" => Some(Self::Debug),
+            "INFO" => Some(Self::Info),
+            "WARN" | "WARNING" => Some(Self::Warning),
+            "ERROR" => Some(Self::Error),
+            "CRITICAL" | "FATAL" => Some(Self::Critical),
+            _ => None,
+        }
+    }
+}
+
+/// A line that an external tap or target wrote to stderr.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEvent {
+    /// The level of the line, if it could be determined.
+    pub level: Option<LogLevel>,
+    /// The line without its level.
+    pub message: String,
+    /// The line as it was written.
+    pub raw: String,
+}
+
+impl LogEvent {
+    /// Parses a line of stderr. The level is read from lines formatted like
+    /// singer-python's logs (`INFO Starting sync`) and like the Singer SDK's
+    /// logs (`2021-03-01 00:00:00,000 | INFO | tap-foo | Starting sync`).
+    /// Lines without a recognizable level, such as the lines of a traceback,
+    /// are kept without a level.
+    pub fn parse<S: Into<String>>(line: S) -> Self {
+        let raw = line.into();
+
+        let parts: Vec<&str> = raw.split(" | ").collect();
+
+        let (level, message) = if parts.len() > 1 {
+            match parts
+                .iter()
+                .position(|part| LogLevel::parse(part).is_some())
+            {
+                Some(index) => (
+                    LogLevel::parse(parts[index]),
+                    parts[parts.len() - 1].to_string(),
+                ),
+                None => (None, raw.clone()),
+            }
+        } else {
+            let mut words = raw.splitn(2, ' ');
+            match words.next().and_then(LogLevel::parse) {
+                Some(level) => (Some(level), words.next().unwrap_or_default().to_string()),
+                None => (None, raw.clone()),
+            }
+        };
+
+        Self {
+            level,
+            message,
+            raw,
+        }
+    }
+}
+
+/// Reads the next line like [BufRead::lines], but replaces invalid UTF-8
+/// instead of failing, so that a child writing e.g. latin-1 can't stop the
+/// line from being read. Returns `None` at the end of the input.
+pub(crate) fn read_line_lossy<R: BufRead>(reader: &mut R) -> std::io::Result<Option<String>> {
+    let mut buf = vec![];
+
+    if reader.read_until(b'\n', &mut buf)? == 0 {
+        return Ok(None);
+    }
+
+    if buf.ends_with(b"\n") {
+        buf.pop();
+        if buf.ends_with(b"\r") {
+            buf.pop();
+        }
+    }
+
+    Ok(Some(String::from_utf8_lossy(&buf).into_owned()))
+}
+
+/// Reads the child's stderr on a separate thread, so a child that writes a lot
+/// to stderr can't block while its stdout is being read.
+pub(crate) struct StderrReader {
+    handle: JoinHandle<std::io::Result<VecDeque<String>>>,
+}
+
+impl StderrReader {
+    /// Starts reading the stderr, calling the handler with each line.
+    pub(crate) fn spawn<R>(stderr: R, handler: Option<LogHandler>) -> Self
+    where
+        R: Read + Send + 'static,
+    {
+        let handle = std::thread::spawn(move || {
+            let mut tail = VecDeque::new();
+            let mut tail_bytes = 0;
+
+            let mut stderr = BufReader::new(stderr);
+
+            while let Some(line) = read_line_lossy(&mut stderr)? {
+                if let Some(handler) = &handler {
+                    handler(&LogEvent::parse(line.clone()));
+                }
+
+                tail_bytes += line.len() + 1;
+                tail.push_back(line);
+
+                // the last line is always kept, even when it's longer than the
+                // limit on its own
+                while tail_bytes > STDERR_TAIL_BYTES && tail.len() > 1 {
+                    let line = tail.pop_front().expect("the tail isn't empty");
+                    tail_bytes -= line.len() + 1;
+                }
+            }
+
+            Ok(tail)
+        });
+
+        Self { handle }
+    }
+
+    /// Waits for the child to close its stderr and returns the last lines
+    /// that it wrote, up to [STDERR_TAIL_BYTES].
+    pub(crate) fn finish(self) -> Result<String> {
+        let tail = self
+            .handle
+            .join()
+            .map_err(|_| Error::OtherError("the thread reading stderr panicked"))??;
+
+        Ok(Vec::from(tail).join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod test_logs {
+    use super::*;
+
+    #[test]
+    fn it_parses_log_lines() {
+        let event = LogEvent::parse("INFO Starting sync of stream users");
+        assert_eq!(event.level, Some(LogLevel::Info));
+        assert_eq!(event.message, "Starting sync of stream users");
+
+        let event = LogEvent::parse("2021-03-01 00:00:00,000 | WARNING  | tap-foo | Rate limited");
+        assert_eq!(event.level, Some(LogLevel::Warning));
+        assert_eq!(event.message, "Rate limited");
+
+        let event = LogEvent::parse("Traceback (most recent call last):");
+        assert_eq!(event.level, None);
+        assert_eq!(event.message, event.raw);
+    }
+
+    #[test]
+    fn it_keeps_the_tail_of_stderr() {
+        let stderr: String = (0..10_000)
+            .map(|n| format!("CRITICAL line {}\n", n))
+            .collect();
+
+        let events = Arc::new(std::sync::Mutex::new(vec![]));
+        let handler: LogHandler = {
+            let events = events.clone();
+            Arc::new(move |event: &LogEvent| events.lock().unwrap().push(event.clone()))
+        };
+
+        let tail = StderrReader::spawn(std::io::Cursor::new(stderr), Some(handler))
+            .finish()
+            .unwrap();
+
+        assert_eq!(events.lock().unwrap().len(), 10_000);
+        assert!(tail.len() <= STDERR_TAIL_BYTES);
+        assert!(tail.len() > STDERR_TAIL_BYTES - 100);
+        assert!(tail.starts_with("CRITICAL line "));
+        assert!(tail.ends_with("CRITICAL line 9999"));
+    }
+
+    #[test]
+    fn it_reads_invalid_utf8() {
+        let stderr = b"INFO caf\xe9 opened\r\nINFO done\n".to_vec();
+
+        let tail = StderrReader::spawn(std::io::Cursor::new(stderr), None)
+            .finish()
+            .unwrap();
+
+        assert_eq!(tail, "INFO caf\u{fffd} opened\nINFO done");
+    }
+}
@@ -0,0 +1,92 @@
+use std::{io::BufReader, process::ChildStdout};
+
+use super::{
+    command_error,
+    files::Arguments,
+    logs::{read_line_lossy, StderrReader},
+    process::{ActivityReader, SupervisedChild},
+};
+use crate::{Error, Message, Result};
+
+/// Iterates over the messages that an external tap writes to stdout, see
+/// [ExternalTap::messages](super::ExternalTap::messages).
+///
+/// Each line is parsed into a [Message]; a line that isn't a valid message,
+/// including a line that isn't valid UTF-8, results in
+/// [Error::MalformedMessage] with its line number, after which the iteration
+/// can continue with the next line. Once the iterator is exhausted,
+/// [Messages::finish] checks whether the tap exited successfully.
+///
+/// Dropping the iterator before the tap has exited kills the tap.
+pub struct Messages {
+    child: SupervisedChild,
+    stdout: BufReader<ActivityReader<ChildStdout>>,
+    line_number: usize,
+    stderr: StderrReader,
+    /// Keeps the temporary files the tap reads alive until it has exited.
+    _args: Arguments,
+}
+
+impl Messages {
+    pub(crate) fn new(child: SupervisedChild, stderr: StderrReader, args: Arguments) -> Self {
+        let stdout = child
+            .take(|child| child.stdout.take())
+            .expect("piped stdout should be Some");
+
+        Self {
+            stdout: BufReader::new(child.track(stdout)),
+            child,
+            line_number: 0,
+            stderr,
+            _args: args,
+        }
+    }
+
+    /// The number of the last line that was read, starting at 1.
+    pub fn line_number(&self) -> usize {
+        self.line_number
+    }
+
+    /// Waits for the tap to exit, failing with [Error::CommandError] when it
+    /// exits unsuccessfully, or with [Error::Timeout] or [Error::Cancelled]
+    /// when it was stopped. Any messages that haven't been read yet are
+    /// skipped.
+    pub fn finish(mut self) -> Result<()> {
+        let remaining = std::io::copy(&mut self.stdout, &mut std::io::sink());
+
+        let status = self.child.wait(remaining)?;
+
+        let stderr = self.stderr.finish()?;
+
+        if !status.success() {
+            return Err(command_error(status, stderr));
+        }
+
+        Ok(())
+    }
+}
+
+impl Iterator for Messages {
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match read_line_lossy(&mut self.stdout) {
+                Ok(Some(line)) => line,
+                Ok(None) => return None,
+                Err(err) => return Some(Err(Error::IoError(err))),
+            };
+
+            self.line_number += 1;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            return Some(
+                serde_json::from_str(&line)
+                    .map_err(|err| Error::MalformedMessage(self.line_number, err)),
+            );
+        }
+    }
+}
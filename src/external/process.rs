@@ -0,0 +1,295 @@
+use std::{
+    io::Read,
+    process::{Child, Command, ExitStatus},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::{Error, Result};
+
+/// How often the watchdog checks whether a child should be stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The time a child is given to exit after being asked to terminate before it
+/// is killed.
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Cancels a running external tap, e.g. from another thread. Cancelling
+/// terminates the tap the same way as a timeout, and the run fails with
+/// [Error::Cancelled].
+///
+/// Once cancelled, a handle stays cancelled, so any later runs that use it are
+/// stopped immediately.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationHandle(Arc<AtomicBool>);
+
+impl CancellationHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Which of the timeouts of an [ExternalTap](super::ExternalTap) expired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutKind {
+    /// The tap ran for longer than its timeout.
+    Overall,
+    /// The tap didn't write anything to stdout for longer than its idle
+    /// timeout while its output was being waited for.
+    Idle,
+}
+
+/// The limits on how long a child may run.
+#[derive(Debug, Clone)]
+pub(crate) struct Limits {
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) grace_period: Duration,
+    pub(crate) cancellation: CancellationHandle,
+}
+
+/// Why the watchdog stopped the child.
+#[derive(Debug, Clone, Copy)]
+enum Stop {
+    Timeout(TimeoutKind, Duration),
+    Cancelled,
+}
+
+impl From<Stop> for Error {
+    fn from(stop: Stop) -> Self {
+        match stop {
+            Stop::Timeout(kind, after) => Error::Timeout(kind, after),
+            Stop::Cancelled => Error::Cancelled,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    child: Mutex<Child>,
+    /// When the pending read of the child's output started, which is `None`
+    /// while nothing is waiting for output.
+    waiting_since: Mutex<Option<Instant>>,
+    finished: AtomicBool,
+    stop: Mutex<Option<Stop>>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Puts the child in its own process group, so that terminating it also
+/// terminates any processes it started.
+pub(crate) fn isolate(command: &mut Command) -> &mut Command {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+
+    command
+}
+
+/// A child process that is watched by a separate thread, which terminates it
+/// when it's cancelled or exceeds one of its timeouts. The child is killed
+/// when this is dropped before the child has exited.
+pub(crate) struct SupervisedChild {
+    shared: Arc<Shared>,
+    watchdog: Option<JoinHandle<()>>,
+}
+
+impl SupervisedChild {
+    pub(crate) fn new(child: Child, limits: Limits) -> Self {
+        let shared = Arc::new(Shared {
+            child: Mutex::new(child),
+            waiting_since: Mutex::new(None),
+            finished: AtomicBool::new(false),
+            stop: Mutex::new(None),
+        });
+
+        let watchdog = {
+            let shared = shared.clone();
+            std::thread::spawn(move || watch(&shared, &limits))
+        };
+
+        Self {
+            shared,
+            watchdog: Some(watchdog),
+        }
+    }
+
+    /// Takes a pipe of the child, e.g. `|child| child.stdout.take()`.
+    pub(crate) fn take<T>(&self, f: impl FnOnce(&mut Child) -> Option<T>) -> Option<T> {
+        f(&mut lock(&self.shared.child))
+    }
+
+    /// Wraps the reader so that the idle timeout only counts the time spent
+    /// waiting in its reads. A consumer that is slow to read, and so blocks
+    /// the child on a full pipe, doesn't make the child idle.
+    pub(crate) fn track<R: Read>(&self, reader: R) -> ActivityReader<R> {
+        ActivityReader {
+            reader,
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Waits for the child to exit after its output has been read. When
+    /// reading the output failed, the child is killed since nothing is reading
+    /// its output anymore, and the error is returned.
+    ///
+    /// Fails with [Error::Timeout] or [Error::Cancelled] when the watchdog
+    /// stopped the child.
+    pub(crate) fn wait<T>(&mut self, read: std::io::Result<T>) -> Result<ExitStatus> {
+        if read.is_err() {
+            kill(&self.shared);
+        }
+
+        let status = loop {
+            if let Some(status) = lock(&self.shared.child).try_wait()? {
+                break status;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        };
+
+        self.shared.finished.store(true, Ordering::SeqCst);
+        if let Some(watchdog) = self.watchdog.take() {
+            let _ = watchdog.join();
+        }
+
+        if let Some(stop) = *lock(&self.shared.stop) {
+            return Err(stop.into());
+        }
+
+        read?;
+
+        Ok(status)
+    }
+}
+
+impl Drop for SupervisedChild {
+    fn drop(&mut self) {
+        self.shared.finished.store(true, Ordering::SeqCst);
+        kill(&self.shared);
+
+        if let Some(watchdog) = self.watchdog.take() {
+            let _ = watchdog.join();
+        }
+    }
+}
+
+/// Runs until the child has finished, terminating it when it should be
+/// stopped.
+fn watch(shared: &Shared, limits: &Limits) {
+    let started = Instant::now();
+
+    while !shared.finished.load(Ordering::SeqCst) {
+        let stop = if limits.cancellation.is_cancelled() {
+            Some(Stop::Cancelled)
+        } else {
+            let idle = lock(&shared.waiting_since).map(|since| since.elapsed());
+
+            let overall = limits
+                .timeout
+                .filter(|timeout| started.elapsed() >= *timeout)
+                .map(|timeout| Stop::Timeout(TimeoutKind::Overall, timeout));
+            let idle = limits
+                .idle_timeout
+                .filter(|timeout| matches!(idle, Some(idle) if idle >= *timeout))
+                .map(|timeout| Stop::Timeout(TimeoutKind::Idle, timeout));
+
+            overall.or(idle)
+        };
+
+        if let Some(stop) = stop {
+            *lock(&shared.stop) = Some(stop);
+            terminate(shared, limits.grace_period);
+            return;
+        }
+
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Asks the child to exit with SIGTERM and kills it if it's still running
+/// after the grace period. Platforms without signals kill the child right away.
+fn terminate(shared: &Shared, grace_period: Duration) {
+    #[cfg(unix)]
+    {
+        if signal(shared, libc::SIGTERM) {
+            let deadline = Instant::now() + grace_period;
+
+            while Instant::now() < deadline && !shared.finished.load(Ordering::SeqCst) {
+                if let Ok(Some(_)) = lock(&shared.child).try_wait() {
+                    return;
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = grace_period;
+
+    kill(shared);
+}
+
+/// Kills the child if it's still running.
+fn kill(shared: &Shared) {
+    #[cfg(unix)]
+    signal(shared, libc::SIGKILL);
+
+    let mut child = lock(&shared.child);
+    if let Ok(None) = child.try_wait() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+/// Sends the signal to the child's process group if the child is still
+/// running, returning whether it was sent. The child is locked while checking
+/// so that it can't be reaped, and its id reused, before the signal is sent.
+#[cfg(unix)]
+fn signal(shared: &Shared, signal: libc::c_int) -> bool {
+    let mut child = lock(&shared.child);
+
+    match child.try_wait() {
+        Ok(None) => {
+            // the child was started with `isolate`, so its id is also the id of
+            // its process group
+            let group = -(child.id() as libc::pid_t);
+            unsafe { libc::kill(group, signal) == 0 }
+        }
+        _ => false,
+    }
+}
+
+/// A reader that records how long its current read has been waiting, see
+/// [SupervisedChild::track].
+pub(crate) struct ActivityReader<R> {
+    reader: R,
+    shared: Arc<Shared>,
+}
+
+impl<R: Read> Read for ActivityReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        *lock(&self.shared.waiting_since) = Some(Instant::now());
+        let read = self.reader.read(buf);
+        *lock(&self.shared.waiting_since) = None;
+
+        read
+    }
+}
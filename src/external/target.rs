@@ -0,0 +1,323 @@
+use std::{
+    io::{BufReader, Read, Write},
+    path::PathBuf,
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::Arc,
+    thread::JoinHandle,
+};
+
+use serde_json::Value;
+
+use super::{
+    command_error,
+    logs::{read_line_lossy, StderrReader},
+    wait, LogEvent, LogHandler,
+};
+use crate::{
+    tap::MessageWriter,
+    target::{Context, Target},
+    ActivateVersion, Error, Message, Record, Result, Schema, State,
+};
+
+/// Allows for sending messages to a target that isn't implemented in rust,
+/// such as `target-postgres`. The target is executed in a child process with
+/// `--config`, messages are written to its stdin and the states it emits on
+/// stdout are collected.
+///
+/// As a [Target], the child process is started by the first message and
+/// [Target::process_reader] passes the messages through to it unchanged,
+/// storing the last state the child emitted in the context once it exits.
+pub struct ExternalTarget {
+    /// The target to execute, which is resolved like
+    /// [ExternalTap::tap](super::ExternalTap::tap).
+    pub target: String,
+    /// The config file passed to the target with `--config`.
+    pub config_path: PathBuf,
+    /// Called with each line the target writes to stderr.
+    pub log_handler: Option<LogHandler>,
+    process: Option<TargetProcess>,
+}
+
+impl ExternalTarget {
+    pub fn new<S: Into<String>, P: Into<PathBuf>>(target: S, config_path: P) -> Self {
+        Self {
+            target: target.into(),
+            config_path: config_path.into(),
+            log_handler: None,
+            process: None,
+        }
+    }
+
+    /// Sets the handler that is called with each line the target writes to
+    /// stderr, e.g. to forward the target's logs.
+    pub fn with_log_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&LogEvent) + Send + Sync + 'static,
+    {
+        self.log_handler = Some(Arc::new(handler));
+        self
+    }
+
+    /// Starts the target in a new child process.
+    pub fn spawn(&self) -> Result<TargetProcess> {
+        let child = Command::new(&self.target)
+            .arg("--config")
+            .arg(&self.config_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(Error::ExecError)?;
+
+        Ok(TargetProcess::new(child, self.log_handler.clone()))
+    }
+
+    /// Closes the stdin of the child process started by the [Target] methods
+    /// and waits for it to exit, returning the last state that it emitted.
+    pub fn finish(&mut self) -> Result<Option<State>> {
+        match self.process.take() {
+            Some(process) => process.finish(),
+            None => Ok(None),
+        }
+    }
+
+    fn write_message(&mut self, message: &Message) -> Result<()> {
+        if self.process.is_none() {
+            self.process = Some(self.spawn()?);
+        }
+
+        let process = self.process.as_mut().expect("the process was just spawned");
+
+        if let Err(err) = process.write_message(message) {
+            // the child most likely exited, which is more useful to report than
+            // the broken pipe
+            self.finish()?;
+            return Err(err);
+        }
+
+        Ok(())
+    }
+}
+
+impl Target for ExternalTarget {
+    fn process_record(&mut self, record: Record) -> Result<()> {
+        self.write_message(&Message::Record(record))
+    }
+
+    fn process_state(&mut self, state: State) -> Result<()> {
+        self.write_message(&Message::State(state))
+    }
+
+    fn process_activate_version(&mut self, activate_version: ActivateVersion) -> Result<()> {
+        self.write_message(&Message::ActivateVersion(activate_version))
+    }
+
+    /// Unknown messages are passed to the child, which decides how to handle
+    /// them.
+    fn process_unknown(&mut self, _context: &mut Context, ty: String, raw: Value) -> Result<()> {
+        self.write_message(&Message::Unknown { ty, raw })
+    }
+
+    /// The schema is passed to the child, which is responsible for validating
+    /// the records.
+    fn process_schema(&mut self, _context: &mut Context, schema: Schema) -> Result<()> {
+        self.write_message(&Message::Schema(schema))
+    }
+
+    fn process_reader<R: Read>(&mut self, context: &mut Context, mut reader: R) -> Result<()> {
+        if self.process.is_none() {
+            self.process = Some(self.spawn()?);
+        }
+
+        let process = self.process.as_mut().expect("the process was just spawned");
+        let copied = std::io::copy(&mut reader, process).and_then(|_| process.flush());
+
+        let state = self.finish()?;
+        copied?;
+
+        if state.is_some() {
+            context.state = state;
+        }
+
+        Ok(())
+    }
+}
+
+/// A running external target, see [ExternalTarget::spawn].
+///
+/// Dropping the process before calling [TargetProcess::finish] kills it.
+pub struct TargetProcess {
+    child: Option<Child>,
+    writer: Option<MessageWriter<ChildStdin>>,
+    states: Option<JoinHandle<std::io::Result<Vec<State>>>>,
+    stderr: Option<StderrReader>,
+}
+
+impl TargetProcess {
+    fn new(mut child: Child, log_handler: Option<LogHandler>) -> Self {
+        let stdin = child.stdin.take().expect("piped stdin should be Some");
+        let stdout = child.stdout.take().expect("piped stdout should be Some");
+        let stderr = child.stderr.take().expect("piped stderr should be Some");
+
+        let states = std::thread::spawn(move || {
+            let mut states = vec![];
+            let mut stdout = BufReader::new(stdout);
+
+            while let Some(line) = read_line_lossy(&mut stdout)? {
+                // targets may write other output to stdout, only lines of JSON
+                // are considered to be states
+                if let Ok(value) = serde_json::from_str::<Value>(&line) {
+                    states.push(State::from(value));
+                }
+            }
+
+            Ok(states)
+        });
+
+        Self {
+            child: Some(child),
+            writer: Some(MessageWriter::new(stdin)),
+            states: Some(states),
+            stderr: Some(StderrReader::spawn(stderr, log_handler)),
+        }
+    }
+
+    fn writer(&mut self) -> &mut MessageWriter<ChildStdin> {
+        self.writer
+            .as_mut()
+            .expect("the writer is only taken when finishing")
+    }
+
+    pub fn write_message(&mut self, message: &Message) -> Result<()> {
+        self.writer().write_message(message)
+    }
+
+    /// Closes the target's stdin and waits for it to exit, returning the last
+    /// state it emitted. Fails with [Error::CommandError] when the target exits
+    /// unsuccessfully.
+    pub fn finish(mut self) -> Result<Option<State>> {
+        // a target that exited early has closed its stdin, in which case its
+        // exit status explains what happened
+        let closed = match self
+            .writer
+            .take()
+            .expect("the writer is only taken once")
+            .into_inner()
+        {
+            Err(Error::IoError(err)) if err.kind() != std::io::ErrorKind::BrokenPipe => Err(err),
+            Err(Error::IoError(_)) | Ok(_) => Ok(()),
+            Err(err) => Err(std::io::Error::other(err.to_string())),
+        };
+
+        let child = self.child.take().expect("the child is only taken once");
+        let status = wait(child, closed);
+
+        let states = self
+            .states
+            .take()
+            .expect("the states are only taken once")
+            .join()
+            .map_err(|_| Error::OtherError("the thread reading stdout panicked"))?;
+
+        let stderr = self
+            .stderr
+            .take()
+            .expect("the stderr is only taken once")
+            .finish()?;
+
+        let status = status?;
+        if !status.success() {
+            return Err(command_error(status, stderr));
+        }
+
+        Ok(states?.pop())
+    }
+}
+
+impl Write for TargetProcess {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writer().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Write::flush(self.writer())
+    }
+}
+
+impl Drop for TargetProcess {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test_external_target {
+    use super::*;
+    use crate::external::test_external::script;
+
+    static MESSAGES: &str = r#"{"type":"SCHEMA","stream":"users","schema":{"type":"object"},"key_properties":["id"]}
+{"type":"RECORD","stream":"users","record":{"id":1}}
+{"type":"STATE","value":{"bookmarks":{"users":{"id":1}}}}
+"#;
+
+    #[test]
+    fn it_collects_the_emitted_state() {
+        let target = script(
+            "target-states",
+            r#"count=0
+while read line; do count=$((count + 1)); done
+echo "INFO received $count messages" >&2
+echo 'not a state'
+printf 'caf\351\n'
+echo '{"bookmarks":{"users":{"id":0}}}'
+echo "{\"bookmarks\":{\"users\":{\"id\":$count}}}""#,
+        );
+
+        let mut external = ExternalTarget::new(target.to_str().unwrap(), "config.json");
+        let mut context = Context::default();
+
+        external
+            .process_reader(&mut context, MESSAGES.as_bytes())
+            .unwrap();
+
+        assert_eq!(
+            context.state.unwrap().get_bookmark("users", "id"),
+            Some(&serde_json::json!(3))
+        );
+
+        external
+            .process_record(Record::new("users", serde_json::json!({ "id": 2 })))
+            .unwrap();
+        let state = external.finish().unwrap().unwrap();
+        assert_eq!(
+            state.get_bookmark("users", "id"),
+            Some(&serde_json::json!(1))
+        );
+    }
+
+    #[test]
+    fn it_surfaces_target_failures() {
+        let target = script(
+            "target-failing",
+            r#"cat > /dev/null
+echo "CRITICAL relation users does not exist" >&2
+exit 4"#,
+        );
+
+        let mut external = ExternalTarget::new(target.to_str().unwrap(), "config.json");
+        let err = external
+            .process_reader(&mut Context::default(), MESSAGES.as_bytes())
+            .unwrap_err();
+
+        match err {
+            Error::CommandError(code, stderr) => {
+                assert_eq!(code, Some(4));
+                assert_eq!(stderr, "CRITICAL relation users does not exist");
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+    }
+}
@@ -1,14 +1,43 @@
 use serde::{Deserialize, Serialize};
 
+mod about;
+pub mod cli;
 pub mod external;
+pub mod pipeline;
 pub mod tap;
 pub mod target;
 
 // pub use tap::{Tap, TapReader};
+pub use about::{About, Capability};
 
 pub type DateTime = chrono::DateTime<chrono::Utc>;
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Reads the file and deserializes its JSON contents.
+pub(crate) fn read_json<T: serde::de::DeserializeOwned>(path: &std::path::Path) -> Result<T> {
+    let file = std::fs::File::open(path).map_err(|err| match err.kind() {
+        std::io::ErrorKind::NotFound => Error::FileNotFound(path.display().to_string()),
+        _ => Error::IoError(err),
+    })?;
+
+    Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+}
+
+/// Joins the errors into one line for an error message.
+fn join<T: std::fmt::Display>(errors: &[T]) -> String {
+    errors
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Checks the config against the schema, failing with [Error::InvalidConfig]
+/// that lists every error.
+pub(crate) fn check_config(schema: &target::JSONSchema, config: &serde_json::Value) -> Result<()> {
+    schema.validate(config).map_err(Error::InvalidConfig)
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     /// Occurs when a command fails to execute. This is differs from
@@ -18,38 +47,221 @@ pub enum Error {
     ExecError(std::io::Error),
     /// Occurs when the command exits unsuccessfully. It contains the exit code
     /// as well as the output from stderr.
-    #[error("Command failed to exit successfully. Exit code ({:0?}) \n stderr: {1}")]
+    #[error("Command failed to exit successfully. Exit code ({0:?}) \n stderr: {1}")]
     CommandError(Option<i32>, String),
+    /// Occurs when the command is terminated by a signal rather than exiting.
+    /// It contains the signal as well as the output from stderr.
+    #[error("Command was terminated by signal {0} \n stderr: {1}")]
+    SignalError(i32, String),
+    /// Occurs when a command is stopped because it ran for too long.
+    #[error("Command timed out after {1:?} ({0:?} timeout)")]
+    Timeout(external::TimeoutKind, std::time::Duration),
+    /// Occurs when a command is stopped through its cancellation handle.
+    #[error("Command was cancelled")]
+    Cancelled,
     #[error("IOError {0}")]
     IoError(#[from] std::io::Error),
     #[error("Failed to deserialize the value {0}")]
     DeserializationError(#[from] serde_json::Error),
+    #[error("Line {0} is not a valid message: {1}")]
+    MalformedMessage(usize, serde_json::Error),
+    /// Occurs when a tap's discover output isn't a valid catalog. It contains
+    /// the path of the invalid value within the output.
+    #[error("The catalog is invalid at {0}: {1}")]
+    InvalidCatalog(String, serde_json::Error),
     #[error("Trying to send a message in a channel where all receivers are dropped")]
     SendError(Box<dyn std::error::Error + Send + Sync + 'static>),
     #[error("Invalid conversion :: found ({0}) expected ({1})")]
     InvalidConversion(&'static str, &'static str),
     #[error("File could not be found: {0}")]
     FileNotFound(String),
+    #[error("Invalid usage: {0}")]
+    UsageError(String),
     #[error("Option not set: {0}")]
     OptionNotSet(&'static str),
-    #[error("Option is not valid: {0}")]
-    InvalidOption(&'static str),
 
     #[error("The JSON schema for stream {0} has not been registered")]
     JSONSchemaNotRegistered(String),
     #[error("The value could not be compiled to a JSON schema")]
     JSONSchemaCompilationError,
-    #[error("The value was invalid for the JSON schema. {0}")]
-    JSONSchemaValidationError(String),
+    #[error("{0}")]
+    JSONSchemaValidationError(Box<target::InvalidRecord>),
+    #[error("The config is invalid: {}", join(.0))]
+    InvalidConfig(Vec<target::ValidationError>),
+    #[error("Invalid selection rule: {0}")]
+    InvalidSelectionRule(String),
+    #[error("Received a message with an unknown type: {0}")]
+    UnknownMessageType(String),
     #[error("An unexpected error occurred. {0}")]
     OtherError(&'static str),
 }
 
+/// The state of a tap, used to resume syncing where the previous run stopped.
+///
+/// The value is an arbitrary JSON value, however the methods on this type follow
+/// the conventional layout used by singer-python's `bookmarks` module:
+///
+/// ```json
+/// {
+///   "bookmarks": {
+///     "users": { "updated_at": "2021-03-01T00:00:00Z", "offset": {} }
+///   },
+///   "currently_syncing": "users"
+/// }
+/// ```
+///
+/// Keys that aren't part of this layout are preserved.
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct State {
     pub(crate) value: serde_json::Value,
 }
 
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<serde_json::Value> for State {
+    fn from(value: serde_json::Value) -> Self {
+        Self { value }
+    }
+}
+
+impl State {
+    /// Creates a state without any bookmarks.
+    pub fn new() -> Self {
+        Self {
+            value: serde_json::Value::Object(serde_json::Map::new()),
+        }
+    }
+
+    pub fn value(&self) -> &serde_json::Value {
+        &self.value
+    }
+
+    pub fn into_value(self) -> serde_json::Value {
+        self.value
+    }
+
+    /// Returns the bookmark `key` of the stream, if it has been written.
+    pub fn get_bookmark(&self, tap_stream_id: &str, key: &str) -> Option<&serde_json::Value> {
+        self.value.get("bookmarks")?.get(tap_stream_id)?.get(key)
+    }
+
+    /// Sets the bookmark `key` of the stream to `value`, replacing any previous
+    /// value.
+    pub fn write_bookmark<K, V>(&mut self, tap_stream_id: &str, key: K, value: V) -> &mut Self
+    where
+        K: Into<String>,
+        V: Into<serde_json::Value>,
+    {
+        self.stream_bookmarks_mut(tap_stream_id)
+            .insert(key.into(), value.into());
+        self
+    }
+
+    /// Removes the bookmark `key` from the stream, returning its previous
+    /// value.
+    pub fn clear_bookmark(&mut self, tap_stream_id: &str, key: &str) -> Option<serde_json::Value> {
+        self.stream_bookmarks_mut(tap_stream_id).remove(key)
+    }
+
+    /// Removes all of the bookmarks of the stream, returning them.
+    pub fn reset_stream(&mut self, tap_stream_id: &str) -> Option<serde_json::Value> {
+        self.bookmarks_mut().remove(tap_stream_id)
+    }
+
+    /// Returns the offset of the stream, if one has been set.
+    pub fn get_offset(&self, tap_stream_id: &str) -> Option<&serde_json::Value> {
+        self.get_bookmark(tap_stream_id, "offset")
+    }
+
+    /// Sets `offset_key` within the offset of the stream.
+    pub fn set_offset<K, V>(&mut self, tap_stream_id: &str, offset_key: K, value: V) -> &mut Self
+    where
+        K: Into<String>,
+        V: Into<serde_json::Value>,
+    {
+        let bookmarks = self.stream_bookmarks_mut(tap_stream_id);
+        let offset = object_entry(bookmarks, "offset");
+        offset.insert(offset_key.into(), value.into());
+        self
+    }
+
+    /// Resets the offset of the stream to an empty object.
+    pub fn clear_offset(&mut self, tap_stream_id: &str) -> &mut Self {
+        self.stream_bookmarks_mut(tap_stream_id).insert(
+            String::from("offset"),
+            serde_json::Value::Object(serde_json::Map::new()),
+        );
+        self
+    }
+
+    /// Returns the `tap_stream_id` of the stream that was being synced when the
+    /// state was written.
+    pub fn get_currently_syncing(&self) -> Option<&str> {
+        self.value.get("currently_syncing")?.as_str()
+    }
+
+    /// Sets the stream that is currently being synced. Passing `None` clears
+    /// it, which is written as `null` like singer-python does.
+    pub fn set_currently_syncing<S: Into<String>>(
+        &mut self,
+        tap_stream_id: Option<S>,
+    ) -> &mut Self {
+        let value = tap_stream_id
+            .map(|id| serde_json::Value::String(id.into()))
+            .unwrap_or(serde_json::Value::Null);
+
+        self.object_mut()
+            .insert(String::from("currently_syncing"), value);
+        self
+    }
+
+    /// Returns the value as an object, replacing it with an empty object if
+    /// it's any other type of value.
+    fn object_mut(&mut self) -> &mut serde_json::Map<String, serde_json::Value> {
+        if !self.value.is_object() {
+            self.value = serde_json::Value::Object(serde_json::Map::new());
+        }
+
+        self.value
+            .as_object_mut()
+            .expect("the state's value should be an object")
+    }
+
+    fn bookmarks_mut(&mut self) -> &mut serde_json::Map<String, serde_json::Value> {
+        object_entry(self.object_mut(), "bookmarks")
+    }
+
+    fn stream_bookmarks_mut(
+        &mut self,
+        tap_stream_id: &str,
+    ) -> &mut serde_json::Map<String, serde_json::Value> {
+        object_entry(self.bookmarks_mut(), tap_stream_id)
+    }
+}
+
+/// Returns the object stored under `key`, inserting an empty object if the key
+/// doesn't exist or doesn't contain an object.
+fn object_entry<'a>(
+    map: &'a mut serde_json::Map<String, serde_json::Value>,
+    key: &str,
+) -> &'a mut serde_json::Map<String, serde_json::Value> {
+    let entry = map
+        .entry(key)
+        .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+
+    if !entry.is_object() {
+        *entry = serde_json::Value::Object(serde_json::Map::new());
+    }
+
+    entry
+        .as_object_mut()
+        .expect("the entry should have been replaced with an object")
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Schema {
     pub(crate) stream: String,
@@ -62,7 +274,7 @@ pub struct Schema {
 pub struct Record {
     pub stream: String,
     pub record: serde_json::Value,
-    pub version: Option<String>,
+    pub version: Option<i64>,
     pub time_extracted: Option<DateTime>,
 }
 
@@ -77,38 +289,139 @@ impl Record {
     }
 }
 
+/// Signals that the records of the stream written with `version` are now the
+/// complete contents of the stream. Targets use it to swap in a new version of
+/// a table after a full table sync and discard records from older versions.
 #[derive(Debug, Clone, Deserialize, Serialize)]
-#[serde(
-    tag = "type",
-    rename_all(deserialize = "UPPERCASE", serialize = "UPPERCASE")
-)]
+pub struct ActivateVersion {
+    pub stream: String,
+    pub version: i64,
+}
+
+impl ActivateVersion {
+    pub fn new<S: Into<String>>(stream: S, version: i64) -> Self {
+        Self {
+            stream: stream.into(),
+            version,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum Message {
     State(State),
     Schema(Schema),
     Record(Record),
+    ActivateVersion(ActivateVersion),
+    /// A message with a `type` that this crate doesn't support, such as
+    /// `BATCH`, `METRIC` or a vendor extension. The original JSON object is
+    /// kept in `raw` and is written back out unchanged when serialized.
+    Unknown {
+        ty: String,
+        raw: serde_json::Value,
+    },
 }
 
-impl Message {
-    pub fn is_state(&self) -> bool {
-        match self {
-            Message::State(_) => true,
-            _ => false,
+/// The internally tagged representation of the supported messages, used when
+/// deserializing a [Message].
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "SCREAMING_SNAKE_CASE")]
+enum TaggedMessage {
+    State(State),
+    Schema(Schema),
+    Record(Record),
+    ActivateVersion(ActivateVersion),
+}
+
+/// Borrowed version of [TaggedMessage], used when serializing a [Message].
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "SCREAMING_SNAKE_CASE")]
+enum TaggedMessageRef<'a> {
+    State(&'a State),
+    Schema(&'a Schema),
+    Record(&'a Record),
+    ActivateVersion(&'a ActivateVersion),
+}
+
+impl From<TaggedMessage> for Message {
+    fn from(message: TaggedMessage) -> Self {
+        match message {
+            TaggedMessage::State(state) => Self::State(state),
+            TaggedMessage::Schema(schema) => Self::Schema(schema),
+            TaggedMessage::Record(record) => Self::Record(record),
+            TaggedMessage::ActivateVersion(activate_version) => {
+                Self::ActivateVersion(activate_version)
+            }
         }
     }
+}
 
-    pub fn is_schema(&self) -> bool {
+impl Serialize for Message {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
         match self {
-            Message::Schema(_) => true,
-            _ => false,
+            Self::State(state) => TaggedMessageRef::State(state).serialize(serializer),
+            Self::Schema(schema) => TaggedMessageRef::Schema(schema).serialize(serializer),
+            Self::Record(record) => TaggedMessageRef::Record(record).serialize(serializer),
+            Self::ActivateVersion(activate_version) => {
+                TaggedMessageRef::ActivateVersion(activate_version).serialize(serializer)
+            }
+            Self::Unknown { raw, .. } => raw.serialize(serializer),
         }
     }
+}
 
-    pub fn is_record(&self) -> bool {
-        match self {
-            Message::Record(_) => true,
-            _ => false,
+impl<'de> Deserialize<'de> for Message {
+    fn deserialize<D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Self, D::Error> {
+        use serde::de::Error as _;
+
+        let raw = serde_json::Value::deserialize(deserializer)?;
+
+        let ty = raw
+            .get("type")
+            .and_then(serde_json::Value::as_str)
+            .ok_or_else(|| D::Error::missing_field("type"))?
+            .to_string();
+
+        if Self::SUPPORTED_TYPES.contains(&ty.as_str()) {
+            TaggedMessage::deserialize(raw)
+                .map(Self::from)
+                .map_err(D::Error::custom)
+        } else {
+            Ok(Self::Unknown { ty, raw })
         }
     }
+}
+
+impl Message {
+    /// The values of the `type` key for the messages that can be deserialized
+    /// into a variant other than [Message::Unknown].
+    pub const SUPPORTED_TYPES: &'static [&'static str] =
+        &["STATE", "SCHEMA", "RECORD", "ACTIVATE_VERSION"];
+
+    pub fn is_state(&self) -> bool {
+        matches!(self, Message::State(_))
+    }
+
+    pub fn is_schema(&self) -> bool {
+        matches!(self, Message::Schema(_))
+    }
+
+    pub fn is_record(&self) -> bool {
+        matches!(self, Message::Record(_))
+    }
+
+    pub fn is_activate_version(&self) -> bool {
+        matches!(self, Message::ActivateVersion(_))
+    }
+
+    pub fn is_unknown(&self) -> bool {
+        matches!(self, Message::Unknown { .. })
+    }
 
     pub fn as_state(&self) -> Option<&State> {
         match self {
@@ -131,11 +444,31 @@ impl Message {
         }
     }
 
+    pub fn as_activate_version(&self) -> Option<&ActivateVersion> {
+        match self {
+            Message::ActivateVersion(activate_version) => Some(activate_version),
+            _ => None,
+        }
+    }
+
+    /// The stream the message belongs to, which STATE and unknown messages
+    /// don't have.
+    pub fn stream(&self) -> Option<&str> {
+        match self {
+            Message::Schema(schema) => Some(&schema.stream),
+            Message::Record(record) => Some(&record.stream),
+            Message::ActivateVersion(activate_version) => Some(&activate_version.stream),
+            Message::State(_) | Message::Unknown { .. } => None,
+        }
+    }
+
     pub fn ty(&self) -> &'static str {
         match self {
             Self::State { .. } => "status",
             Self::Schema { .. } => "schema",
             Self::Record { .. } => "record",
+            Self::ActivateVersion { .. } => "activate_version",
+            Self::Unknown { .. } => "unknown",
         }
     }
 }
@@ -179,14 +512,21 @@ impl std::convert::TryFrom<Message> for Record {
     }
 }
 
+impl std::convert::TryFrom<Message> for ActivateVersion {
+    type Error = Error;
+
+    fn try_from(m: Message) -> Result<Self> {
+        match m {
+            Message::ActivateVersion(activate_version) => Ok(activate_version),
+            _ => Err(Error::InvalidConversion("activate_version", m.ty())),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     // use super::{external::ExternalTap, *};
 
-    static TAP: &'static str = "/Volumes/CODE/python/env/bin/tap-github";
-    static CONFIG: &'static str = "/Volumes/CODE/python/config.json";
-    static CATALOG: &'static str = "/Volumes/CODE/python/properties.json";
-
     #[test]
     fn it_works() {
         // let mut tap = ExternalTap::new(TAP, CONFIG, None, None);
@@ -199,6 +539,100 @@ mod tests {
         // })
     }
 
+    #[test]
+    fn it_deserializes_activate_version() {
+        let message: super::Message = serde_json::from_str(
+            r#"{"type":"ACTIVATE_VERSION","stream":"users","version":1614556800000}"#,
+        )
+        .unwrap();
+
+        let activate_version = message.as_activate_version().unwrap();
+
+        assert_eq!(activate_version.stream, "users");
+        assert_eq!(activate_version.version, 1614556800000);
+        assert_eq!(
+            serde_json::to_value(&message).unwrap()["type"],
+            serde_json::json!("ACTIVATE_VERSION")
+        );
+    }
+
+    #[test]
+    fn it_deserializes_record_version() {
+        let message: super::Message = serde_json::from_str(
+            r#"{"type":"RECORD","stream":"users","record":{"id":1},"version":1614556800000}"#,
+        )
+        .unwrap();
+
+        assert_eq!(message.as_record().unwrap().version, Some(1614556800000));
+    }
+
+    #[test]
+    fn it_keeps_unknown_messages() {
+        let line = r#"{"type":"METRIC","metric_type":"counter","value":5}"#;
+        let message: super::Message = serde_json::from_str(line).unwrap();
+
+        match &message {
+            super::Message::Unknown { ty, .. } => assert_eq!(ty, "METRIC"),
+            _ => panic!("expected an unknown message"),
+        }
+
+        assert_eq!(
+            serde_json::to_value(&message).unwrap(),
+            serde_json::from_str::<serde_json::Value>(line).unwrap()
+        );
+    }
+
+    #[test]
+    fn it_writes_bookmarks() {
+        let mut state = super::State::new();
+
+        state
+            .write_bookmark("users", "updated_at", "2021-03-01T00:00:00Z")
+            .set_offset("users", "page", 2)
+            .set_currently_syncing(Some("users"));
+
+        assert_eq!(
+            state.value(),
+            &serde_json::json!({
+                "bookmarks": {
+                    "users": {
+                        "updated_at": "2021-03-01T00:00:00Z",
+                        "offset": { "page": 2 }
+                    }
+                },
+                "currently_syncing": "users"
+            })
+        );
+
+        state.clear_offset("users");
+        assert_eq!(state.get_offset("users"), Some(&serde_json::json!({})));
+
+        assert_eq!(
+            state.clear_bookmark("users", "updated_at"),
+            Some(serde_json::json!("2021-03-01T00:00:00Z"))
+        );
+        assert_eq!(state.get_bookmark("users", "updated_at"), None);
+
+        state.set_currently_syncing::<String>(None);
+        assert_eq!(state.get_currently_syncing(), None);
+    }
+
+    #[test]
+    fn it_preserves_unknown_state_keys() {
+        let mut state = super::State::from(serde_json::json!({
+            "bookmarks": { "orders": { "id": 10 } },
+            "version": 3
+        }));
+
+        state.write_bookmark("orders", "id", 11);
+
+        assert_eq!(
+            state.get_bookmark("orders", "id"),
+            Some(&serde_json::json!(11))
+        );
+        assert_eq!(state.value()["version"], serde_json::json!(3));
+    }
+
     // // #[test]
     // fn test_target() {
     //     #[derive(Debug, Default)]
@@ -0,0 +1,420 @@
+use std::{
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{sync_channel, Receiver, SyncSender},
+};
+
+use crate::{
+    tap::{self, MessageWriter, Tap},
+    target::{self, Target},
+    Error, Result, State,
+};
+
+/// The number of messages that can be buffered between the tap and the target
+/// before the tap has to wait for the target.
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// Connects a tap to a target, the equivalent of
+/// `tap --state state.json | target > state.json`.
+///
+/// The tap is run on a separate thread and its messages are sent to the target
+/// through a bounded channel. Once both have finished, the last state that the
+/// target confirmed is written to the state file, even when either of them
+/// failed, and is passed to the tap as its state on the next run.
+///
+/// Both sides can be implemented in Rust or be external programs, using
+/// [ExternalTap](crate::external::ExternalTap) and
+/// [ExternalTarget](crate::external::ExternalTarget).
+pub struct Pipeline<T: Tap, U: Target> {
+    pub tap: T,
+    pub tap_context: tap::Context<T::Config>,
+    pub target: U,
+    pub target_context: target::Context,
+    pub state_path: Option<PathBuf>,
+    pub capacity: usize,
+}
+
+impl<T, U> Pipeline<T, U>
+where
+    T: Tap + Send,
+    T::Config: Send,
+    U: Target,
+{
+    pub fn new(tap: T, tap_context: tap::Context<T::Config>, target: U) -> Self {
+        Self {
+            tap,
+            tap_context,
+            target,
+            target_context: target::Context::default(),
+            state_path: None,
+            capacity: DEFAULT_CAPACITY,
+        }
+    }
+
+    /// Sets the file that the state is read from before running the tap and
+    /// written to after the target has finished. The file doesn't need to
+    /// exist for the first run.
+    pub fn with_state_path<P: Into<PathBuf>>(mut self, state_path: P) -> Self {
+        self.state_path = Some(state_path.into());
+        self
+    }
+
+    pub fn with_target_context(mut self, target_context: target::Context) -> Self {
+        self.target_context = target_context;
+        self
+    }
+
+    /// Sets the number of messages that are buffered between the tap and the
+    /// target, defaults to [DEFAULT_CAPACITY].
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Runs the tap and the target until the tap has written all of its
+    /// messages and the target has processed them, returning the last state
+    /// the target confirmed.
+    ///
+    /// When the target fails its error is returned, since the tap's error is
+    /// then most likely caused by the target no longer receiving messages.
+    /// The state file is written before the error is returned, so the next run
+    /// resumes from the last state the target confirmed.
+    pub fn run(&mut self) -> Result<Option<State>> {
+        if let Some(state_path) = self.state_path.as_ref().filter(|path| path.exists()) {
+            self.tap_context.read_state(state_path)?;
+        }
+
+        self.target_context.state = None;
+
+        let (sender, receiver) = sync_channel(self.capacity);
+
+        let tap = &mut self.tap;
+        let tap_context = &mut self.tap_context;
+        let target = &mut self.target;
+        let target_context = &mut self.target_context;
+
+        let (tap_result, target_result) = std::thread::scope(|scope| {
+            let tap_thread = scope.spawn(move || {
+                let mut writer = MessageWriter::new(ChannelWriter::new(sender));
+                tap.sync(tap_context, &mut writer)?;
+                writer.flush()
+            });
+
+            let target_result = target.process_reader(target_context, ChannelReader::new(receiver));
+
+            let tap_result = tap_thread
+                .join()
+                .unwrap_or(Err(Error::OtherError("the tap's thread panicked")));
+
+            (tap_result, target_result)
+        });
+
+        let state = self.target_context.state.clone();
+
+        let write_result = match (&state, &self.state_path) {
+            (Some(state), Some(state_path)) => write_state(state_path, state),
+            _ => Ok(()),
+        };
+
+        target_result?;
+        tap_result?;
+        write_result?;
+
+        Ok(state)
+    }
+}
+
+/// Writes the state to a temporary file next to the state file and then
+/// renames it, so the state file is never left partially written.
+fn write_state(path: &Path, state: &State) -> Result<()> {
+    let mut file_name = path
+        .file_name()
+        .ok_or_else(|| Error::FileNotFound(path.display().to_string()))?
+        .to_os_string();
+    file_name.push(".tmp");
+    let tmp_path = path.with_file_name(file_name);
+
+    {
+        let mut file = std::fs::File::create(&tmp_path)?;
+        serde_json::to_writer(&mut file, state.value())?;
+        file.sync_all()?;
+    }
+
+    std::fs::rename(&tmp_path, path)?;
+
+    Ok(())
+}
+
+/// Sends each line written to it through the channel.
+struct ChannelWriter {
+    sender: SyncSender<Vec<u8>>,
+    line: Vec<u8>,
+}
+
+impl ChannelWriter {
+    fn new(sender: SyncSender<Vec<u8>>) -> Self {
+        Self {
+            sender,
+            line: vec![],
+        }
+    }
+
+    fn send(&mut self, line: Vec<u8>) -> std::io::Result<()> {
+        self.sender.send(line).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "the target stopped receiving messages",
+            )
+        })
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut rest = buf;
+
+        while let Some(index) = rest.iter().position(|&b| b == b'\n') {
+            self.line.extend_from_slice(&rest[..=index]);
+            let line = std::mem::take(&mut self.line);
+            self.send(line)?;
+            rest = &rest[index + 1..];
+        }
+
+        self.line.extend_from_slice(rest);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.line.is_empty() {
+            let line = std::mem::take(&mut self.line);
+            self.send(line)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads the lines received through the channel until the sender is dropped.
+struct ChannelReader {
+    receiver: Receiver<Vec<u8>>,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl ChannelReader {
+    fn new(receiver: Receiver<Vec<u8>>) -> Self {
+        Self {
+            receiver,
+            buffer: vec![],
+            position: 0,
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.buffer.len() {
+            match self.receiver.recv() {
+                Ok(buffer) => {
+                    self.buffer = buffer;
+                    self.position = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+
+        let read = (&self.buffer[self.position..]).read(buf)?;
+        self.position += read;
+
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod test_pipeline {
+    use super::*;
+    use crate::{tap::Catalog, Record, Schema};
+
+    struct CounterTap;
+
+    impl Tap for CounterTap {
+        type Config = ();
+
+        fn discover(&self, _context: &mut tap::Context<()>) -> Result<Catalog> {
+            Ok(Catalog { streams: vec![] })
+        }
+
+        fn sync<W: Write>(
+            &mut self,
+            context: &mut tap::Context<()>,
+            writer: &mut MessageWriter<W>,
+        ) -> Result<()> {
+            let start = context
+                .state()
+                .and_then(|state| state.get_bookmark("counter", "n"))
+                .and_then(serde_json::Value::as_i64)
+                .unwrap_or(0);
+
+            writer.write_schema(Schema {
+                stream: String::from("counter"),
+                schema: serde_json::json!({ "type": "object" }),
+                key_properties: vec![String::from("n")],
+                bookmark_properties: None,
+            })?;
+
+            let mut state = context.state().cloned().unwrap_or_default();
+
+            for n in start + 1..=start + 3 {
+                writer.write_record(Record::new("counter", serde_json::json!({ "n": n })))?;
+                state.write_bookmark("counter", "n", n);
+                writer.write_state(state.clone())?;
+            }
+
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct CollectingTarget {
+        numbers: Vec<i64>,
+    }
+
+    impl Target for CollectingTarget {
+        fn process_record(&mut self, record: Record) -> Result<()> {
+            self.numbers.push(record.record["n"].as_i64().unwrap());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_persists_state_between_runs() {
+        let state_path =
+            std::env::temp_dir().join(format!("singer-pipeline-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&state_path);
+
+        let mut pipeline =
+            Pipeline::new(CounterTap, tap::Context::new(), CollectingTarget::default())
+                .with_state_path(&state_path)
+                .with_capacity(1);
+
+        pipeline.run().unwrap();
+        let state = pipeline.run().unwrap().unwrap();
+
+        assert_eq!(pipeline.target.numbers, vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(
+            state.get_bookmark("counter", "n"),
+            Some(&serde_json::json!(6))
+        );
+
+        let saved: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&state_path).unwrap()).unwrap();
+        assert_eq!(&saved, state.value());
+
+        std::fs::remove_file(&state_path).unwrap();
+    }
+
+    #[test]
+    fn it_reports_target_failures() {
+        struct FailingTarget;
+
+        impl Target for FailingTarget {
+            fn process_record(&mut self, _record: Record) -> Result<()> {
+                Err(Error::OtherError("disk full"))
+            }
+        }
+
+        let mut pipeline = Pipeline::new(CounterTap, tap::Context::new(), FailingTarget);
+
+        assert!(matches!(
+            pipeline.run(),
+            Err(Error::OtherError("disk full"))
+        ));
+    }
+
+    #[test]
+    fn it_persists_state_when_the_tap_fails() {
+        struct FailingTap;
+
+        impl Tap for FailingTap {
+            type Config = ();
+
+            fn discover(&self, _context: &mut tap::Context<()>) -> Result<Catalog> {
+                Ok(Catalog { streams: vec![] })
+            }
+
+            fn sync<W: Write>(
+                &mut self,
+                _context: &mut tap::Context<()>,
+                writer: &mut MessageWriter<W>,
+            ) -> Result<()> {
+                writer.write_schema(Schema {
+                    stream: String::from("counter"),
+                    schema: serde_json::json!({ "type": "object" }),
+                    key_properties: vec![String::from("n")],
+                    bookmark_properties: None,
+                })?;
+
+                let mut state = State::default();
+
+                for n in 1..=2 {
+                    writer.write_record(Record::new("counter", serde_json::json!({ "n": n })))?;
+                    state.write_bookmark("counter", "n", n);
+                    writer.write_state(state.clone())?;
+                }
+
+                Err(Error::OtherError("connection reset"))
+            }
+        }
+
+        let state_path = std::env::temp_dir().join(format!(
+            "singer-pipeline-failure-{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&state_path);
+
+        let mut pipeline =
+            Pipeline::new(FailingTap, tap::Context::new(), CollectingTarget::default())
+                .with_state_path(&state_path);
+
+        assert!(matches!(
+            pipeline.run(),
+            Err(Error::OtherError("connection reset"))
+        ));
+
+        let mut pipeline =
+            Pipeline::new(CounterTap, tap::Context::new(), CollectingTarget::default())
+                .with_state_path(&state_path);
+        pipeline.run().unwrap();
+
+        assert_eq!(pipeline.target.numbers, vec![3, 4, 5]);
+
+        std::fs::remove_file(&state_path).unwrap();
+    }
+
+    #[test]
+    fn it_keeps_the_tap_context_when_the_state_is_invalid() {
+        let state_path = std::env::temp_dir().join(format!(
+            "singer-pipeline-invalid-{}.json",
+            std::process::id()
+        ));
+        std::fs::write(&state_path, "{").unwrap();
+
+        let mut pipeline = Pipeline::new(
+            CounterTap,
+            tap::Context::new().with_config(()),
+            CollectingTarget::default(),
+        )
+        .with_state_path(&state_path);
+
+        assert!(matches!(
+            pipeline.run(),
+            Err(Error::DeserializationError(_))
+        ));
+        assert!(pipeline.tap_context.config().is_ok());
+
+        std::fs::remove_file(&state_path).unwrap();
+        pipeline.run().unwrap();
+        assert_eq!(pipeline.target.numbers, vec![1, 2, 3]);
+    }
+}
@@ -1,66 +1,199 @@
-use std::io::{BufWriter, Write};
+use std::{
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+mod catalog;
+mod select;
+
+pub use catalog::{
+    Catalog, FieldPath, Inclusion, Metadata, MetadataValues, ReplicationMethod, Stream,
+};
+pub use select::SelectionRule;
+
+use crate::{
+    check_config, read_json, target::JSONSchema, About, ActivateVersion, Error, Message, Record,
+    Result, Schema, State,
+};
+
+/// The configuration of a tap's run. It holds the paths of the files passed to
+/// the tap along with their parsed contents, so the files are only read once.
+///
+/// `C` is the type of the tap's config, which is deserialized from the config
+/// file.
+#[derive(Debug)]
+pub struct Context<C = serde_json::Value> {
+    config_path: Option<PathBuf>,
+    config: Option<C>,
+    catalog_path: Option<PathBuf>,
+    properties_path: Option<PathBuf>,
+    catalog: Option<Catalog>,
+    state_path: Option<PathBuf>,
+    state: Option<State>,
+}
 
-use serde::{Deserialize, Serialize};
+impl<C> Default for Context<C> {
+    fn default() -> Self {
+        Self {
+            config_path: None,
+            config: None,
+            catalog_path: None,
+            properties_path: None,
+            catalog: None,
+            state_path: None,
+            state: None,
+        }
+    }
+}
 
-use crate::{Error, Message, Record, Result, Schema, State};
+impl<C> Context<C> {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Catalog {
-    pub streams: Vec<Stream>,
-}
+    /// Sets the config without a config file, replacing a config that was
+    /// loaded from a file.
+    pub fn with_config(mut self, config: C) -> Self {
+        self.config = Some(config);
+        self.config_path = None;
+        self
+    }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Stream {
-    pub stream: String,
-    pub tap_stream_id: String,
-    pub schema: serde_json::Value,
-    pub table_name: Option<String>,
-    pub metadata: Option<Vec<Metadata>>,
-}
+    /// Sets the catalog without a catalog file, replacing a catalog that was
+    /// loaded from a file.
+    pub fn with_catalog(mut self, catalog: Catalog) -> Self {
+        self.catalog = Some(catalog);
+        self.catalog_path = None;
+        self.properties_path = None;
+        self
+    }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Metadata {
-    pub metadata: serde_json::Value,
-    pub breadcrumb: Vec<String>,
-}
+    /// Sets the state without a state file, replacing a state that was loaded
+    /// from a file.
+    pub fn with_state(mut self, state: State) -> Self {
+        self.state = Some(state);
+        self.state_path = None;
+        self
+    }
+
+    /// Reads and parses the catalog passed with `--catalog`.
+    pub fn load_catalog<P: Into<PathBuf>>(mut self, path: P) -> Result<Self> {
+        let path = path.into();
+        self.catalog = Some(read_json(&path)?);
+        self.catalog_path = Some(path);
+        Ok(self)
+    }
+
+    /// Reads and parses the catalog passed with the legacy `--properties`
+    /// option.
+    pub fn load_properties<P: Into<PathBuf>>(mut self, path: P) -> Result<Self> {
+        let path = path.into();
+        self.catalog = Some(read_json(&path)?);
+        self.properties_path = Some(path);
+        Ok(self)
+    }
+
+    /// Reads and parses the state passed with `--state`.
+    pub fn load_state<P: Into<PathBuf>>(mut self, path: P) -> Result<Self> {
+        self.read_state(path)?;
+        Ok(self)
+    }
+
+    /// Reads and parses the state like [Context::load_state], replacing the
+    /// current state. The context is left unchanged when the state file can't
+    /// be read.
+    pub fn read_state<P: Into<PathBuf>>(&mut self, path: P) -> Result<()> {
+        let path = path.into();
+        let state = State::from(read_json::<serde_json::Value>(&path)?);
+        self.state = Some(state);
+        self.state_path = Some(path);
+        Ok(())
+    }
+
+    /// Returns the config, failing with [Error::OptionNotSet] when it hasn't
+    /// been set or loaded.
+    pub fn config(&self) -> Result<&C> {
+        self.config
+            .as_ref()
+            .ok_or_else(|| Error::OptionNotSet("config"))
+    }
+
+    pub fn catalog(&self) -> Option<&Catalog> {
+        self.catalog.as_ref()
+    }
+
+    pub fn state(&self) -> Option<&State> {
+        self.state.as_ref()
+    }
+
+    pub fn config_path(&self) -> Option<&Path> {
+        self.config_path.as_deref()
+    }
+
+    pub fn catalog_path(&self) -> Option<&Path> {
+        self.catalog_path.as_deref()
+    }
+
+    pub fn properties_path(&self) -> Option<&Path> {
+        self.properties_path.as_deref()
+    }
 
-#[derive(Default, Debug, Serialize, Deserialize)]
-pub struct Context {
-    pub config_path: Option<String>,
-    pub catalog_path: Option<String>,
-    pub state_path: Option<String>,
-    pub properties_path: Option<String>,
+    pub fn state_path(&self) -> Option<&Path> {
+        self.state_path.as_deref()
+    }
 }
 
-impl Context {
-    pub fn get_option(&self, option: &'static str) -> Result<&str> {
-        (match option {
-            "config" => Ok(self.config_path.as_deref()),
-            "catalog" => Ok(self.catalog_path.as_deref()),
-            "state" => Ok(self.state_path.as_deref()),
-            "properties" => Ok(self.properties_path.as_deref()),
-            _ => Err(Error::InvalidOption(option)),
-        })
-        .and_then(|value| value.ok_or_else(|| Error::OptionNotSet(option)))
+impl<C: DeserializeOwned> Context<C> {
+    /// Reads and parses the config passed with `--config`.
+    pub fn load_config<P: Into<PathBuf>>(mut self, path: P) -> Result<Self> {
+        let path = path.into();
+        self.config = Some(read_json(&path)?);
+        self.config_path = Some(path);
+        Ok(self)
     }
 
-    pub fn set_option<S: Into<String>>(
-        &mut self,
-        option: &'static str,
-        value: S,
-    ) -> Result<Option<String>> {
-        match option {
-            "config" => Ok(self.config_path.replace(value.into())),
-            "catalog" => Ok(self.catalog_path.replace(value.into())),
-            "state" => Ok(self.state_path.replace(value.into())),
-            "properties" => Ok(self.properties_path.replace(value.into())),
-            _ => Err(Error::InvalidOption(option)),
-        }
+    /// Reads the config passed with `--config` and checks it against the
+    /// schema before parsing it, failing with [Error::InvalidConfig] that
+    /// lists every problem with the config.
+    pub fn load_config_with_schema<P: Into<PathBuf>>(
+        mut self,
+        path: P,
+        schema: &JSONSchema,
+    ) -> Result<Self> {
+        let path = path.into();
+        let config = read_json(&path)?;
+        check_config(schema, &config)?;
+        self.config = Some(serde_json::from_value(config)?);
+        self.config_path = Some(path);
+        Ok(self)
     }
 }
 
 /// Create a Tap in Rust that conforms to the Singer specification.
 pub trait Tap {
+    /// The tap's config, which is read from the file passed with `--config`.
+    type Config: DeserializeOwned;
+
+    /// Describes the tap for `--about`. Taps that return `None` don't support
+    /// `--about`.
+    fn about() -> Option<About>
+    where
+        Self: Sized,
+    {
+        None
+    }
+
+    /// The JSON schema the config is checked against before the tap runs,
+    /// which defaults to the config schema of [Tap::about].
+    fn config_schema() -> Option<serde_json::Value>
+    where
+        Self: Sized,
+    {
+        Self::about().and_then(|about| about.config_schema)
+    }
+
     /// Runs the tap in "Discovery Mode".
     ///
     /// > Discovery mode provides a way for a tap to describe the data streams
@@ -74,7 +207,7 @@ pub trait Tap {
     /// [1]: https://github.com/singer-io/getting-started/blob/master/docs/DISCOVERY_MODE.md#discovery-mode
     ///
     /// See [Singer's Discovery Mode documentation](https://github.com/singer-io/getting-started/blob/master/docs/DISCOVERY_MODE.md#discovery-mode) for more information.
-    fn discover(&self, context: &mut Context) -> Result<Catalog>;
+    fn discover(&self, context: &mut Context<Self::Config>) -> Result<Catalog>;
 
     /// Run the tap in "Sync Mode". The tap should write Schema, Record, and
     /// State messages to the writer.
@@ -82,7 +215,7 @@ pub trait Tap {
     /// See Singer's [Sync Mode documentation](https://github.com/singer-io/getting-started/blob/master/docs/SYNC_MODE.md#sync-mode) for more information.
     fn sync<W: Write>(
         &mut self,
-        context: &mut Context,
+        context: &mut Context<Self::Config>,
         writer: &mut MessageWriter<W>,
     ) -> Result<()>;
 }
@@ -189,13 +322,17 @@ impl<W: Write> MessageWriter<W> {
         self.write_message(&Message::Schema(schema))
     }
 
+    pub fn write_activate_version(&mut self, activate_version: ActivateVersion) -> Result<()> {
+        self.write_message(&Message::ActivateVersion(activate_version))
+    }
+
     pub fn flush(&mut self) -> Result<()> {
         self.inner.flush()?;
         Ok(())
     }
 
     pub fn write_line(&mut self) -> Result<()> {
-        self.inner.write(b"\n")?;
+        self.inner.write_all(b"\n")?;
         Ok(())
     }
 
@@ -256,4 +393,41 @@ mod test_tap {
 
         assert_eq!(buffer, expected);
     }
+
+    #[test]
+    fn it_loads_the_context_from_files() {
+        #[derive(serde::Deserialize)]
+        struct Config {
+            api_key: String,
+        }
+
+        let dir = std::env::temp_dir().join(format!("singer-context-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let config_path = dir.join("config.json");
+        let state_path = dir.join("state.json");
+        std::fs::write(&config_path, r#"{"api_key":"secret"}"#).unwrap();
+        std::fs::write(&state_path, r#"{"bookmarks":{"users":{"id":5}}}"#).unwrap();
+
+        let context = super::Context::<Config>::new()
+            .load_config(&config_path)
+            .unwrap()
+            .load_state(&state_path)
+            .unwrap();
+
+        assert_eq!(context.config().unwrap().api_key, "secret");
+        assert_eq!(context.config_path(), Some(config_path.as_path()));
+        assert_eq!(
+            context.state().unwrap().get_bookmark("users", "id"),
+            Some(&serde_json::json!(5))
+        );
+        assert!(context.catalog().is_none());
+
+        assert!(matches!(
+            super::Context::<Config>::new().load_catalog(dir.join("missing.json")),
+            Err(crate::Error::FileNotFound(_))
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,529 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Catalog {
+    pub streams: Vec<Stream>,
+}
+
+impl Catalog {
+    pub fn get_stream(&self, tap_stream_id: &str) -> Option<&Stream> {
+        self.streams
+            .iter()
+            .find(|stream| stream.tap_stream_id == tap_stream_id)
+    }
+
+    pub fn get_stream_mut(&mut self, tap_stream_id: &str) -> Option<&mut Stream> {
+        self.streams
+            .iter_mut()
+            .find(|stream| stream.tap_stream_id == tap_stream_id)
+    }
+
+    /// Returns the streams that should be synced. See [Stream::is_selected].
+    pub fn selected_streams(&self) -> Vec<&Stream> {
+        self.streams
+            .iter()
+            .filter(|stream| stream.is_selected())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Stream {
+    pub stream: String,
+    pub tap_stream_id: String,
+    pub schema: Value,
+    pub table_name: Option<String>,
+    pub metadata: Option<Vec<Metadata>>,
+    /// Keys of the stream that aren't modelled above, such as `key_properties`
+    /// or `is_view`, which are written back out when the catalog is serialized.
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+impl Stream {
+    /// Returns the metadata with the breadcrumb, e.g. `[]` for the stream
+    /// itself or `["properties", "id"]` for the `id` property.
+    pub fn find_metadata<S: AsRef<str>>(&self, breadcrumb: &[S]) -> Option<&MetadataValues> {
+        self.metadata
+            .as_ref()?
+            .iter()
+            .find(|metadata| metadata.has_breadcrumb(breadcrumb))
+            .map(|metadata| &metadata.metadata)
+    }
+
+    pub fn find_metadata_mut<S: AsRef<str>>(
+        &mut self,
+        breadcrumb: &[S],
+    ) -> Option<&mut MetadataValues> {
+        self.metadata
+            .as_mut()?
+            .iter_mut()
+            .find(|metadata| metadata.has_breadcrumb(breadcrumb))
+            .map(|metadata| &mut metadata.metadata)
+    }
+
+    /// Returns the metadata with the breadcrumb, inserting empty metadata if
+    /// the stream doesn't have any for the breadcrumb yet.
+    pub fn metadata_entry<S: AsRef<str>>(&mut self, breadcrumb: &[S]) -> &mut MetadataValues {
+        let entries = self.metadata.get_or_insert_with(Vec::new);
+
+        let index = match entries
+            .iter()
+            .position(|metadata| metadata.has_breadcrumb(breadcrumb))
+        {
+            Some(index) => index,
+            None => {
+                let breadcrumb = breadcrumb.iter().map(|s| s.as_ref().to_string());
+                entries.push(Metadata::new(
+                    breadcrumb.collect(),
+                    MetadataValues::default(),
+                ));
+                entries.len() - 1
+            }
+        };
+
+        &mut entries[index].metadata
+    }
+
+    /// Returns the metadata that applies to the stream as a whole.
+    pub fn stream_metadata(&self) -> Option<&MetadataValues> {
+        self.find_metadata::<&str>(&[])
+    }
+
+    /// Returns the metadata of one of the stream's top level properties.
+    pub fn property_metadata(&self, property: &str) -> Option<&MetadataValues> {
+        self.find_metadata(&["properties", property])
+    }
+
+    /// Whether the stream should be synced, which is resolved from the
+    /// stream's metadata (the entry with an empty breadcrumb) with the same
+    /// rules singer-python uses:
+    ///
+    /// - `inclusion: automatic` is always selected
+    /// - `inclusion: unsupported` is never selected
+    /// - otherwise `selected` is used when it's set, falling back to
+    ///   `selected-by-default`
+    /// - a stream without any of these is not selected
+    pub fn is_selected(&self) -> bool {
+        should_sync(self.stream_metadata(), false)
+    }
+
+    /// Returns the paths of the properties that should be synced, e.g.
+    /// `["id"]` or `["address", "city"]` for nested properties.
+    ///
+    /// Top level properties are resolved like [Stream::is_selected]. Nested
+    /// properties are only selected when their parent is, and inherit the
+    /// parent's selection unless their own metadata says otherwise. A selected
+    /// object is returned along with its selected nested properties.
+    ///
+    /// The selection of the stream itself isn't taken into account.
+    pub fn selected_fields(&self) -> Vec<FieldPath> {
+        let mut fields = vec![];
+
+        self.collect_selected_fields(&self.schema, &mut vec![], &mut vec![], false, &mut fields);
+
+        fields
+    }
+
+    fn collect_selected_fields(
+        &self,
+        schema: &Value,
+        breadcrumb: &mut Vec<String>,
+        path: &mut FieldPath,
+        default: bool,
+        fields: &mut Vec<FieldPath>,
+    ) {
+        let properties = match schema.get("properties").and_then(Value::as_object) {
+            Some(properties) => properties,
+            None => return,
+        };
+
+        for (name, property_schema) in properties {
+            breadcrumb.extend_from_slice(&[String::from("properties"), name.clone()]);
+            path.push(name.clone());
+
+            if should_sync(self.find_metadata(breadcrumb), default) {
+                fields.push(path.clone());
+                self.collect_selected_fields(property_schema, breadcrumb, path, true, fields);
+            }
+
+            path.pop();
+            breadcrumb.truncate(breadcrumb.len() - 2);
+        }
+    }
+}
+
+/// The path of a property within a stream's schema, made up of the names of
+/// the property and its parents.
+pub type FieldPath = Vec<String>;
+
+/// Resolves whether a stream or property should be synced, see
+/// [Stream::is_selected]. The default is used when the metadata doesn't
+/// determine the selection.
+fn should_sync(metadata: Option<&MetadataValues>, default: bool) -> bool {
+    let metadata = match metadata {
+        Some(metadata) => metadata,
+        None => return default,
+    };
+
+    match metadata.inclusion {
+        Some(Inclusion::Automatic) => true,
+        Some(Inclusion::Unsupported) => false,
+        _ => metadata
+            .selected
+            .or(metadata.selected_by_default)
+            .unwrap_or(default),
+    }
+}
+
+/// A metadata entry of a stream in the catalog. The breadcrumb is the path to
+/// the part of the schema that the metadata applies to, an empty breadcrumb
+/// refers to the stream itself.
+///
+/// See Singer's [metadata documentation](https://github.com/singer-io/getting-started/blob/master/docs/DISCOVERY_MODE.md#metadata)
+/// for more information.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Metadata {
+    pub metadata: MetadataValues,
+    pub breadcrumb: Vec<String>,
+}
+
+impl Metadata {
+    pub fn new(breadcrumb: Vec<String>, metadata: MetadataValues) -> Self {
+        Self {
+            metadata,
+            breadcrumb,
+        }
+    }
+
+    pub fn has_breadcrumb<S: AsRef<str>>(&self, breadcrumb: &[S]) -> bool {
+        self.breadcrumb.len() == breadcrumb.len()
+            && self
+                .breadcrumb
+                .iter()
+                .zip(breadcrumb)
+                .all(|(a, b)| a == b.as_ref())
+    }
+}
+
+/// The values of a metadata entry. Keys that aren't part of the Singer
+/// specification are kept in `other` so that they are written back out when
+/// the catalog is serialized.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct MetadataValues {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub selected: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inclusion: Option<Inclusion>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub selected_by_default: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replication_method: Option<ReplicationMethod>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replication_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub valid_replication_keys: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forced_replication_method: Option<ReplicationMethod>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub table_key_properties: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub view_key_properties: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+/// Whether a stream or property can be selected. Values this crate doesn't
+/// know about are kept as [Inclusion::Other].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum Inclusion {
+    /// The user can choose whether it's synced.
+    Available,
+    /// It's always synced, regardless of whether it's selected.
+    Automatic,
+    /// It can't be synced.
+    Unsupported,
+    Other(String),
+}
+
+impl Inclusion {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Inclusion::Available => "available",
+            Inclusion::Automatic => "automatic",
+            Inclusion::Unsupported => "unsupported",
+            Inclusion::Other(other) => other,
+        }
+    }
+}
+
+impl From<String> for Inclusion {
+    fn from(inclusion: String) -> Self {
+        match inclusion.as_str() {
+            "available" => Inclusion::Available,
+            "automatic" => Inclusion::Automatic,
+            "unsupported" => Inclusion::Unsupported,
+            _ => Inclusion::Other(inclusion),
+        }
+    }
+}
+
+impl From<Inclusion> for String {
+    fn from(inclusion: Inclusion) -> Self {
+        match inclusion {
+            Inclusion::Other(other) => other,
+            inclusion => inclusion.as_str().to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for Inclusion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// How a stream is replicated. Methods this crate doesn't know about are kept
+/// as [ReplicationMethod::Other].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum ReplicationMethod {
+    FullTable,
+    Incremental,
+    LogBased,
+    Other(String),
+}
+
+impl ReplicationMethod {
+    pub fn as_str(&self) -> &str {
+        match self {
+            ReplicationMethod::FullTable => "FULL_TABLE",
+            ReplicationMethod::Incremental => "INCREMENTAL",
+            ReplicationMethod::LogBased => "LOG_BASED",
+            ReplicationMethod::Other(other) => other,
+        }
+    }
+}
+
+impl From<String> for ReplicationMethod {
+    fn from(method: String) -> Self {
+        match method.as_str() {
+            "FULL_TABLE" => ReplicationMethod::FullTable,
+            "INCREMENTAL" => ReplicationMethod::Incremental,
+            "LOG_BASED" => ReplicationMethod::LogBased,
+            _ => ReplicationMethod::Other(method),
+        }
+    }
+}
+
+impl From<ReplicationMethod> for String {
+    fn from(method: ReplicationMethod) -> Self {
+        match method {
+            ReplicationMethod::Other(other) => other,
+            method => method.as_str().to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for ReplicationMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test_catalog {
+    use super::*;
+
+    static CATALOG: &str = r#"{
+  "streams": [
+    {
+      "stream": "users",
+      "tap_stream_id": "public-users",
+      "schema": {
+        "type": "object",
+        "properties": {
+          "id": { "type": "integer" },
+          "email": { "type": ["null", "string"] }
+        }
+      },
+      "metadata": [
+        {
+          "breadcrumb": [],
+          "metadata": {
+            "selected": true,
+            "table-key-properties": ["id"],
+            "forced-replication-method": "INCREMENTAL",
+            "valid-replication-keys": ["updated_at"],
+            "schema-name": "public"
+          }
+        },
+        {
+          "breadcrumb": ["properties", "id"],
+          "metadata": { "inclusion": "automatic" }
+        }
+      ]
+    }
+  ]
+}"#;
+
+    #[test]
+    fn it_parses_typed_metadata() {
+        let catalog: Catalog = serde_json::from_str(CATALOG).unwrap();
+        let stream = catalog.get_stream("public-users").unwrap();
+
+        let metadata = stream.stream_metadata().unwrap();
+        assert_eq!(metadata.selected, Some(true));
+        assert_eq!(metadata.table_key_properties, Some(vec!["id".to_string()]));
+        assert_eq!(
+            metadata.forced_replication_method,
+            Some(ReplicationMethod::Incremental)
+        );
+        assert_eq!(metadata.other["schema-name"], Value::from("public"));
+
+        assert_eq!(
+            stream.property_metadata("id").unwrap().inclusion,
+            Some(Inclusion::Automatic)
+        );
+        assert!(stream.property_metadata("email").is_none());
+    }
+
+    fn stream_with_metadata(metadata: Value) -> Stream {
+        serde_json::from_value(serde_json::json!({
+            "stream": "users",
+            "tap_stream_id": "users",
+            "schema": {
+                "type": "object",
+                "properties": {
+                    "id": { "type": "integer" },
+                    "email": { "type": "string" },
+                    "password": { "type": "string" },
+                    "address": {
+                        "type": "object",
+                        "properties": {
+                            "city": { "type": "string" },
+                            "street": { "type": "string" }
+                        }
+                    }
+                }
+            },
+            "metadata": metadata
+        }))
+        .unwrap()
+    }
+
+    fn paths(fields: &[&str]) -> Vec<FieldPath> {
+        fields
+            .iter()
+            .map(|field| field.split('.').map(String::from).collect())
+            .collect()
+    }
+
+    #[test]
+    fn it_resolves_selected_streams() {
+        let selected = stream_with_metadata(serde_json::json!([
+            { "breadcrumb": [], "metadata": { "selected": true } }
+        ]));
+        let by_default = stream_with_metadata(serde_json::json!([
+            { "breadcrumb": [], "metadata": { "selected-by-default": true } }
+        ]));
+        let deselected = stream_with_metadata(serde_json::json!([
+            { "breadcrumb": [], "metadata": { "selected": false, "selected-by-default": true } }
+        ]));
+        let unsupported = stream_with_metadata(serde_json::json!([
+            { "breadcrumb": [], "metadata": { "selected": true, "inclusion": "unsupported" } }
+        ]));
+        let without_metadata = stream_with_metadata(Value::Null);
+
+        assert!(selected.is_selected());
+        assert!(by_default.is_selected());
+        assert!(!deselected.is_selected());
+        assert!(!unsupported.is_selected());
+        assert!(!without_metadata.is_selected());
+
+        let catalog = Catalog {
+            streams: vec![selected, deselected, by_default],
+        };
+        assert_eq!(catalog.selected_streams().len(), 2);
+    }
+
+    #[test]
+    fn it_resolves_selected_fields() {
+        let stream = stream_with_metadata(serde_json::json!([
+            { "breadcrumb": [], "metadata": { "selected": true } },
+            { "breadcrumb": ["properties", "id"], "metadata": { "inclusion": "automatic", "selected": false } },
+            { "breadcrumb": ["properties", "email"], "metadata": { "selected-by-default": true } },
+            { "breadcrumb": ["properties", "password"], "metadata": { "selected": true, "inclusion": "unsupported" } },
+            { "breadcrumb": ["properties", "address"], "metadata": { "selected": true } },
+            { "breadcrumb": ["properties", "address", "properties", "street"], "metadata": { "selected": false } }
+        ]));
+
+        assert_eq!(
+            stream.selected_fields(),
+            paths(&["address", "address.city", "email", "id"])
+        );
+    }
+
+    #[test]
+    fn it_preserves_unknown_metadata_keys() {
+        let mut catalog: Catalog = serde_json::from_str(CATALOG).unwrap();
+
+        catalog
+            .get_stream_mut("public-users")
+            .unwrap()
+            .metadata_entry(&["properties", "email"])
+            .selected = Some(false);
+
+        let value = serde_json::to_value(&catalog).unwrap();
+        let metadata = &value["streams"][0]["metadata"];
+
+        assert_eq!(
+            metadata[0]["metadata"]["schema-name"],
+            Value::from("public")
+        );
+        assert_eq!(
+            metadata[2],
+            serde_json::json!({
+                "breadcrumb": ["properties", "email"],
+                "metadata": { "selected": false }
+            })
+        );
+    }
+
+    #[test]
+    fn it_keeps_unknown_metadata_values() {
+        let metadata: MetadataValues = serde_json::from_value(serde_json::json!({
+            "inclusion": "vendor-managed",
+            "replication-method": "full_table",
+            "forced-replication-method": "FULL_TABLE"
+        }))
+        .unwrap();
+
+        assert_eq!(
+            metadata.inclusion,
+            Some(Inclusion::Other(String::from("vendor-managed")))
+        );
+        assert_eq!(
+            metadata.replication_method,
+            Some(ReplicationMethod::Other(String::from("full_table")))
+        );
+        assert_eq!(
+            metadata.forced_replication_method,
+            Some(ReplicationMethod::FullTable)
+        );
+
+        assert_eq!(
+            serde_json::to_value(&metadata).unwrap(),
+            serde_json::json!({
+                "inclusion": "vendor-managed",
+                "replication-method": "full_table",
+                "forced-replication-method": "FULL_TABLE"
+            })
+        );
+    }
+}
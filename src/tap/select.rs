@@ -0,0 +1,338 @@
+use std::str::FromStr;
+
+use serde_json::Value;
+
+use super::{Catalog, FieldPath, Stream};
+use crate::{Error, Result};
+
+/// A rule that selects or deselects streams and properties of a [Catalog].
+///
+/// Rules are written as `<stream>[.<property>...]`, where each part may use
+/// `*` to match any number of characters and `?` to match a single character.
+/// Prefixing the rule with `!` deselects whatever it matches instead.
+///
+/// - `users` selects the `users` stream without changing its properties
+/// - `users.*` selects the `users` stream and all of its properties
+/// - `!users.password` deselects the `password` property of `users`
+/// - `orders.address.city` selects the nested `city` property
+///
+/// Streams are matched by their `tap_stream_id` or their `stream` name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelectionRule {
+    pub exclude: bool,
+    pub stream: String,
+    pub properties: Vec<String>,
+}
+
+impl FromStr for SelectionRule {
+    type Err = Error;
+
+    fn from_str(rule: &str) -> Result<Self> {
+        let (exclude, pattern) = match rule.trim().strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, rule.trim()),
+        };
+
+        let mut parts = pattern.split('.').map(String::from);
+
+        let stream = parts.next().unwrap_or_default();
+        let properties: Vec<String> = parts.collect();
+
+        if stream.is_empty() || properties.iter().any(String::is_empty) {
+            return Err(Error::InvalidSelectionRule(rule.to_string()));
+        }
+
+        Ok(Self {
+            exclude,
+            stream,
+            properties,
+        })
+    }
+}
+
+impl SelectionRule {
+    pub fn matches_stream(&self, stream: &Stream) -> bool {
+        glob_match(&self.stream, &stream.tap_stream_id) || glob_match(&self.stream, &stream.stream)
+    }
+
+    pub fn matches_property(&self, path: &[String]) -> bool {
+        self.properties.len() == path.len()
+            && self
+                .properties
+                .iter()
+                .zip(path)
+                .all(|(pattern, name)| glob_match(pattern, name))
+    }
+}
+
+impl Catalog {
+    /// Rewrites the `selected` metadata of every stream and property from the
+    /// rules, producing a catalog that can be passed to a tap in sync mode.
+    ///
+    /// Every stream starts out deselected and the rules are applied in order,
+    /// so later rules take precedence over earlier ones. The properties of a
+    /// stream also start out deselected when a rule for its properties matches
+    /// the stream, otherwise their metadata is left as is. Selecting a property
+    /// selects its stream, its parents and its nested properties; deselecting
+    /// a property deselects its nested properties. Streams and properties
+    /// with `inclusion: automatic` are still synced when deselected.
+    pub fn select(&mut self, rules: &[SelectionRule]) -> &mut Self {
+        for stream in &mut self.streams {
+            let paths = property_paths(&stream.schema);
+
+            let matching: Vec<&SelectionRule> = rules
+                .iter()
+                .filter(|rule| rule.matches_stream(stream))
+                .collect();
+
+            stream.metadata_entry::<&str>(&[]).selected = Some(false);
+            if matching.iter().any(|rule| !rule.properties.is_empty()) {
+                for path in &paths {
+                    stream.metadata_entry(&breadcrumb(path)).selected = Some(false);
+                }
+            }
+
+            for rule in matching {
+                if rule.properties.is_empty() {
+                    stream.metadata_entry::<&str>(&[]).selected = Some(!rule.exclude);
+                    continue;
+                }
+
+                for path in paths.iter().filter(|path| rule.matches_property(path)) {
+                    let affected = paths.iter().filter(|other| other.starts_with(path));
+
+                    for other in affected {
+                        stream.metadata_entry(&breadcrumb(other)).selected = Some(!rule.exclude);
+                    }
+
+                    if !rule.exclude {
+                        stream.metadata_entry::<&str>(&[]).selected = Some(true);
+                        for len in 1..path.len() {
+                            stream.metadata_entry(&breadcrumb(&path[..len])).selected = Some(true);
+                        }
+                    }
+                }
+            }
+        }
+
+        self
+    }
+
+    /// Parses the patterns into [SelectionRule]s and applies them with
+    /// [Catalog::select].
+    pub fn select_patterns<S: AsRef<str>>(&mut self, patterns: &[S]) -> Result<&mut Self> {
+        let rules = patterns
+            .iter()
+            .map(|pattern| pattern.as_ref().parse())
+            .collect::<Result<Vec<SelectionRule>>>()?;
+
+        Ok(self.select(&rules))
+    }
+}
+
+/// Returns the paths of all the properties in the schema, including nested
+/// properties.
+fn property_paths(schema: &Value) -> Vec<FieldPath> {
+    fn collect(schema: &Value, path: &mut FieldPath, paths: &mut Vec<FieldPath>) {
+        if let Some(properties) = schema.get("properties").and_then(Value::as_object) {
+            for (name, property_schema) in properties {
+                path.push(name.clone());
+                paths.push(path.clone());
+                collect(property_schema, path, paths);
+                path.pop();
+            }
+        }
+    }
+
+    let mut paths = vec![];
+    collect(schema, &mut vec![], &mut paths);
+    paths
+}
+
+/// Converts the path of a property to its metadata breadcrumb.
+fn breadcrumb(path: &[String]) -> Vec<&str> {
+    path.iter()
+        .flat_map(|name| vec!["properties", name.as_str()])
+        .collect()
+}
+
+/// Matches the text against a pattern where `*` matches any number of
+/// characters and `?` matches exactly one character.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod test_select {
+    use super::*;
+
+    fn catalog() -> Catalog {
+        serde_json::from_value(serde_json::json!({
+            "streams": [
+                {
+                    "stream": "users",
+                    "tap_stream_id": "users",
+                    "schema": {
+                        "type": "object",
+                        "properties": {
+                            "id": { "type": "integer" },
+                            "email": { "type": "string" },
+                            "password": { "type": "string" }
+                        }
+                    },
+                    "metadata": [
+                        { "breadcrumb": [], "metadata": { "selected-by-default": true } }
+                    ]
+                },
+                {
+                    "stream": "orders",
+                    "tap_stream_id": "public-orders",
+                    "schema": {
+                        "type": "object",
+                        "properties": {
+                            "id": { "type": "integer" },
+                            "total": { "type": "number" },
+                            "address": {
+                                "type": "object",
+                                "properties": {
+                                    "city": { "type": "string" },
+                                    "street": { "type": "string" }
+                                }
+                            }
+                        }
+                    }
+                },
+                {
+                    "stream": "events",
+                    "tap_stream_id": "events",
+                    "schema": { "type": "object", "properties": {} }
+                }
+            ]
+        }))
+        .unwrap()
+    }
+
+    fn selected_fields(catalog: &Catalog, tap_stream_id: &str) -> Vec<String> {
+        catalog
+            .get_stream(tap_stream_id)
+            .unwrap()
+            .selected_fields()
+            .into_iter()
+            .map(|path| path.join("."))
+            .collect()
+    }
+
+    #[test]
+    fn it_parses_rules() {
+        let rule: SelectionRule = "!users.address.*".parse().unwrap();
+
+        assert!(rule.exclude);
+        assert_eq!(rule.stream, "users");
+        assert_eq!(rule.properties, vec!["address", "*"]);
+
+        assert!("".parse::<SelectionRule>().is_err());
+        assert!("users..id".parse::<SelectionRule>().is_err());
+    }
+
+    #[test]
+    fn it_matches_globs() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("user*", "users"));
+        assert!(glob_match("*-orders", "public-orders"));
+        assert!(glob_match("u?ers", "users"));
+        assert!(glob_match("*a*b", "xaxxab"));
+        assert!(!glob_match("user", "users"));
+        assert!(!glob_match("*-orders", "orders"));
+    }
+
+    #[test]
+    fn it_selects_streams_and_fields() {
+        let mut catalog = catalog();
+
+        catalog
+            .select_patterns(&[
+                "users.*",
+                "!users.password",
+                "orders.id",
+                "orders.address.city",
+            ])
+            .unwrap();
+
+        let selected: Vec<&str> = catalog
+            .selected_streams()
+            .into_iter()
+            .map(|stream| stream.tap_stream_id.as_str())
+            .collect();
+
+        assert_eq!(selected, vec!["users", "public-orders"]);
+        assert_eq!(selected_fields(&catalog, "users"), vec!["email", "id"]);
+        assert_eq!(
+            selected_fields(&catalog, "public-orders"),
+            vec!["address", "address.city", "id"]
+        );
+    }
+
+    #[test]
+    fn it_deselects_streams() {
+        let mut catalog = catalog();
+
+        catalog.select_patterns(&["*.*", "!users"]).unwrap();
+
+        let selected: Vec<&str> = catalog
+            .selected_streams()
+            .into_iter()
+            .map(|stream| stream.stream.as_str())
+            .collect();
+
+        assert_eq!(selected, vec!["orders"]);
+        assert_eq!(
+            selected_fields(&catalog, "public-orders"),
+            vec!["address", "address.city", "address.street", "id", "total"]
+        );
+    }
+
+    #[test]
+    fn it_keeps_the_properties_of_selected_streams() {
+        let mut catalog = catalog();
+        catalog
+            .get_stream_mut("users")
+            .unwrap()
+            .metadata_entry(&["properties", "email"])
+            .selected = Some(true);
+
+        catalog.select_patterns(&["users"]).unwrap();
+
+        assert!(catalog.get_stream("users").unwrap().is_selected());
+        assert_eq!(selected_fields(&catalog, "users"), vec!["email"]);
+
+        catalog.select_patterns(&["users.id"]).unwrap();
+        assert_eq!(selected_fields(&catalog, "users"), vec!["id"]);
+    }
+}
@@ -1,127 +1,511 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    fmt,
     io::{BufReader, Read},
+    sync::Arc,
 };
 
-use jsonschema::Draft;
+use jsonschema::paths::PathChunk;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::{Error, Message, Record, Result, Schema, State};
+use crate::{About, ActivateVersion, Error, Message, Record, Result, Schema, State};
 
-/// Wraps the [jsonschema::JSONSchema] and stores [serde_json::Value] for the
-/// schema. The [jsonschema::JSONSchema] takes a reference to the
-/// [serde_json::Value], storing the [serde_json::Value] as a raw pointer in the
-/// struct ensures that the reference lives long enough.
-///
-/// When the struct is dropped it converts the [serde_json::Value] back into a
-/// boxed value, allowing for it to be dropped without leaking memory.
-///
-/// # Safety
-/// This struct turns the [serde_json::Value] into a raw pointer and stores it
-/// internally. This raw pointer is then dereferenced in several places which is
-/// an unsafe operation.
+mod dead_letter;
+mod schema_change;
+
+pub use jsonschema::Draft;
+
+pub use dead_letter::{DeadLetter, DeadLetterReader, DeadLetterSink, DeadLetterWriter};
+pub use schema_change::{SchemaChange, SchemaChanges};
+
+/// A compiled [jsonschema::JSONSchema] along with the [serde_json::Value] it
+/// was compiled from.
 ///
-/// The raw pointer as well as the [jsonschema::JSONSchema] that uses the raw
-/// pointer, are not exposed publicly to prevent them from being used after
-/// being freed. When this struct is dropped, the schema is dropped and pointer
-/// is converted back into a boxed value and dropped.
-#[derive(Debug)]
+/// Both are stored behind an [Arc], so cloning is cheap and clones share the
+/// compiled validator. The schema is `Send` and `Sync`, allowing records to be
+/// validated on several threads at once.
+#[derive(Debug, Clone)]
 pub struct JSONSchema {
-    schema: jsonschema::JSONSchema<'static>,
-    value: *mut Value,
+    schema: Arc<jsonschema::JSONSchema>,
+    value: Arc<Value>,
 }
 
 impl JSONSchema {
+    /// Compiles the schema for the draft of its `$schema`, or for Draft 7 when
+    /// it doesn't declare a draft this crate recognizes.
     pub fn new(value: Value) -> Result<Self> {
-        let value = Box::into_raw(Box::new(value));
-
-        let schema = jsonschema::JSONSchema::compile(unsafe { &*value })
-            .map_err(|_| Error::JSONSchemaCompilationError)?;
+        Self::with_default_draft(value, Draft::default())
+    }
 
-        Ok(Self { schema, value })
+    /// Compiles the schema for the draft of its `$schema`, or for the default
+    /// draft when it doesn't declare a draft this crate recognizes.
+    pub fn with_default_draft(value: Value, default: Draft) -> Result<Self> {
+        let draft = detect_draft(&value).unwrap_or(default);
+        Self::with_draft(value, draft)
     }
 
+    /// Compiles the schema for the draft, regardless of its `$schema`.
+    ///
+    /// Formats are validated for every draft, with the lenient `date-time`
+    /// format of Singer and its `singer.decimal` format, see
+    /// [is_singer_date_time] and [is_singer_decimal].
     pub fn with_draft(value: Value, draft: Draft) -> Result<Self> {
-        let value = Box::into_raw(Box::new(value));
         let schema = jsonschema::JSONSchema::options()
             .with_draft(draft)
-            .compile(unsafe { &*value })
-            .map_err(|e| {
-                dbg!(e);
-                Error::JSONSchemaCompilationError
-            })?;
+            .should_validate_formats(true)
+            .with_format("date-time", is_singer_date_time)
+            .with_format("singer.decimal", is_singer_decimal)
+            .compile(&value)
+            .map_err(|_| Error::JSONSchemaCompilationError)?;
+
+        Ok(Self::from_parts(schema, value))
+    }
+
+    fn from_parts(schema: jsonschema::JSONSchema, value: Value) -> Self {
+        Self {
+            schema: Arc::new(schema),
+            value: Arc::new(value),
+        }
+    }
 
-        Ok(Self { value, schema })
+    /// The schema the validator was compiled from.
+    pub fn value(&self) -> &Value {
+        &self.value
     }
 
     pub fn is_valid(&self, value: &Value) -> bool {
         self.schema.is_valid(value)
     }
 
-    pub fn validate(&self, value: &Value) -> Result<()> {
-        self.schema.validate(value).map_err(|mut e| {
-            Error::JSONSchemaValidationError(format!(
-                "{:}",
-                e.next()
-                    .expect("The JSON schema's error iterator should contain at least one error.")
-            ))
-        })
+    /// Validates the value, returning every error rather than only the first.
+    pub fn validate(&self, value: &Value) -> std::result::Result<(), Vec<ValidationError>> {
+        self.schema
+            .validate(value)
+            .map_err(|errors| errors.map(ValidationError::from).collect())
+    }
+}
+
+/// Returns the draft declared by the schema's `$schema`, accepting the URLs of
+/// Draft 4, 6 and 7 with or without the trailing `#` and over http or https.
+pub fn detect_draft(schema: &Value) -> Option<Draft> {
+    let url = schema.get("$schema")?.as_str()?.trim();
+    let url = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+        .unwrap_or(url);
+
+    match url.trim_end_matches('#') {
+        "json-schema.org/draft-04/schema" => Some(Draft::Draft4),
+        "json-schema.org/draft-06/schema" => Some(Draft::Draft6),
+        "json-schema.org/draft-07/schema" => Some(Draft::Draft7),
+        _ => None,
+    }
+}
+
+/// Checks the `date-time` format as leniently as singer-python parses it,
+/// which also accepts timestamps without an offset, with a space instead of
+/// the `T`, or with an offset like `+0000`, as well as plain dates.
+pub fn is_singer_date_time(value: &str) -> bool {
+    const NAIVE_FORMATS: &[&str] = &["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"];
+    const OFFSET_FORMATS: &[&str] = &["%Y-%m-%dT%H:%M:%S%.f%z", "%Y-%m-%d %H:%M:%S%.f%z"];
+
+    chrono::DateTime::parse_from_rfc3339(value).is_ok()
+        || OFFSET_FORMATS
+            .iter()
+            .any(|format| chrono::DateTime::parse_from_str(value, format).is_ok())
+        || NAIVE_FORMATS
+            .iter()
+            .any(|format| chrono::NaiveDateTime::parse_from_str(value, format).is_ok())
+        || chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d").is_ok()
+}
+
+/// Checks the `singer.decimal` format, which taps use for numbers that are
+/// written as strings to keep their precision, e.g. `"-12.50"` or `"1e-3"`.
+pub fn is_singer_decimal(value: &str) -> bool {
+    let digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+
+    let value = value.strip_prefix(['-', '+']).unwrap_or(value);
+    let (mantissa, exponent) = match value.find(['e', 'E']) {
+        Some(index) => (&value[..index], Some(&value[index + 1..])),
+        None => (value, None),
+    };
+
+    let mantissa = match mantissa.split_once('.') {
+        Some((whole, fraction)) => (whole.is_empty() || digits(whole)) && digits(fraction),
+        None => digits(mantissa),
+    };
+    let exponent = match exponent {
+        Some(exponent) => digits(exponent.strip_prefix(['-', '+']).unwrap_or(exponent)),
+        None => true,
+    };
+
+    mantissa && exponent
+}
+
+/// A single way in which a value doesn't match its JSON schema.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValidationError {
+    /// The JSON pointer to the invalid value, e.g. `/address/zip`.
+    pub instance_path: String,
+    /// The JSON pointer to the part of the schema that the value violates,
+    /// e.g. `/properties/address/properties/zip/type`.
+    pub schema_path: String,
+    /// The keyword that the value violates, e.g. `type` or `required`.
+    pub keyword: String,
+    pub message: String,
+}
+
+impl From<jsonschema::ValidationError<'_>> for ValidationError {
+    fn from(error: jsonschema::ValidationError<'_>) -> Self {
+        let keyword = match error.schema_path.last() {
+            Some(PathChunk::Keyword(keyword)) => keyword.to_string(),
+            Some(PathChunk::Property(property)) => property.to_string(),
+            Some(PathChunk::Index(index)) => index.to_string(),
+            None => String::new(),
+        };
+
+        Self {
+            instance_path: error.instance_path.to_string(),
+            schema_path: error.schema_path.to_string(),
+            keyword,
+            message: error.to_string(),
+        }
     }
 }
 
-impl Drop for JSONSchema {
-    fn drop(&mut self) {
-        unsafe { Box::from_raw(self.value) };
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.instance_path.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.instance_path, self.message)
+        }
     }
 }
 
-#[derive(Debug, Default)]
+/// A record that doesn't match the schema of its stream, see
+/// [Error::JSONSchemaValidationError].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct InvalidRecord {
+    pub stream: String,
+    /// The key properties of the record, or the whole record when its stream
+    /// doesn't have key properties.
+    pub record: Value,
+    pub errors: Vec<ValidationError>,
+}
+
+impl fmt::Display for InvalidRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Record {} of stream {} is invalid",
+            self.record, self.stream
+        )?;
+
+        for error in &self.errors {
+            write!(f, "\n  {}", error)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Determines how [Target::process_unknown] handles messages with a type that
+/// this crate doesn't support.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownMessagePolicy {
+    /// Silently skip the message.
+    Ignore,
+    /// Skip the message and write a warning to stderr.
+    #[default]
+    Warn,
+    /// Stop processing with [Error::UnknownMessageType].
+    Error,
+}
+
+/// Determines how records are validated against the schema of their stream,
+/// and how [Target::process_invalid_record] handles records that don't match
+/// it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ValidationPolicy {
+    /// Stop processing with [Error::JSONSchemaValidationError].
+    #[default]
+    Strict,
+    /// Skip the record and write a warning to stderr.
+    Skip,
+    /// Pass the record to [Target::process_dead_letter] and continue. Records
+    /// that [Target::process_record] fails on are passed along as well.
+    DeadLetter,
+    /// Don't validate records, e.g. for trusted taps where validation is the
+    /// bottleneck.
+    Disabled,
+}
+
+#[derive(Default)]
 pub struct Context {
     pub schemas: HashMap<String, JSONSchema>,
+    /// The policy for streams without their own policy in
+    /// [stream_validation_policies](Context::stream_validation_policies).
+    pub validation_policy: ValidationPolicy,
+    pub stream_validation_policies: HashMap<String, ValidationPolicy>,
+    /// The draft of schemas that don't declare a draft with `$schema`, which
+    /// is Draft 4 when it isn't set since Singer schemas are usually written
+    /// for it.
+    pub default_draft: Option<Draft>,
+    /// The SCHEMA message of each stream. The key properties identify invalid
+    /// records, and the message precedes the stream's dead letters.
+    pub schema_messages: HashMap<String, Schema>,
+    /// Where [Context::dead_letter] writes dead letters. Without a sink, the
+    /// dead letters fail with their error instead.
+    pub dead_letter_sink: Option<Box<dyn DeadLetterSink>>,
+    /// The streams whose SCHEMA message has been written to the dead letter
+    /// sink.
+    pub dead_letter_streams: HashSet<String>,
+    pub unknown_message_policy: UnknownMessagePolicy,
+    /// The last state that [Target::process_state] processed successfully,
+    /// which is emitted by the target once it has finished.
+    pub state: Option<State>,
+}
+
+impl fmt::Debug for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Context")
+            .field("schemas", &self.schemas)
+            .field("validation_policy", &self.validation_policy)
+            .field(
+                "stream_validation_policies",
+                &self.stream_validation_policies,
+            )
+            .field("default_draft", &self.default_draft)
+            .field("schema_messages", &self.schema_messages)
+            .field("dead_letter_sink", &self.dead_letter_sink.is_some())
+            .field("dead_letter_streams", &self.dead_letter_streams)
+            .field("unknown_message_policy", &self.unknown_message_policy)
+            .field("state", &self.state)
+            .finish()
+    }
 }
 
 impl Context {
+    /// Returns the validation policy of the stream.
+    pub fn validation_policy(&self, stream: &str) -> ValidationPolicy {
+        self.stream_validation_policies
+            .get(stream)
+            .copied()
+            .unwrap_or(self.validation_policy)
+    }
+
     pub fn has_schema(&self, schema: &Schema) -> bool {
         self.schemas.contains_key(&schema.stream)
     }
 
+    /// Compiles the schema of the SCHEMA message for the draft of its
+    /// `$schema`, or for the [default_draft](Context::default_draft), and
+    /// registers it for the stream, replacing the stream's previous schema.
     pub fn insert_schema(&mut self, schema: &Schema) -> Result<()> {
-        dbg!(schema);
-
-        let json_schema = JSONSchema::with_draft(schema.schema.clone(), Draft::Draft4)?;
+        let draft = self.default_draft.unwrap_or(Draft::Draft4);
+        let json_schema = JSONSchema::with_default_draft(schema.schema.clone(), draft)?;
 
         self.schemas.insert(schema.stream.clone(), json_schema);
+        self.schema_messages
+            .insert(schema.stream.clone(), schema.clone());
+        // the next dead letter of the stream is preceded by the new schema
+        self.dead_letter_streams.remove(&schema.stream);
 
         Ok(())
     }
 
+    /// Sets where dead letters are written, e.g. a [DeadLetterWriter].
+    pub fn with_dead_letter_sink<S: DeadLetterSink + 'static>(mut self, sink: S) -> Self {
+        self.dead_letter_sink = Some(Box::new(sink));
+        self
+    }
+
+    /// Writes the message to the dead letter sink along with the error,
+    /// preceded by the SCHEMA message of its stream when it's the first dead
+    /// letter of the stream. Fails with the error when there is no sink.
+    pub fn dead_letter(&mut self, message: Message, error: Error) -> Result<()> {
+        let sink = match &mut self.dead_letter_sink {
+            Some(sink) => sink,
+            None => return Err(error),
+        };
+
+        let stream = message.stream().unwrap_or_default().to_owned();
+
+        if !self.dead_letter_streams.contains(&stream) {
+            if let Some(schema) = self.schema_messages.get(&stream) {
+                sink.write(&DeadLetter::schema(schema.clone()))?;
+            }
+            self.dead_letter_streams.insert(stream.clone());
+        }
+
+        sink.write(&DeadLetter::new(stream, message, &error))
+    }
+
+    /// Validates the record against the schema of its stream, failing with
+    /// [Error::JSONSchemaValidationError] that contains every error.
     pub fn validate_record(&self, record: &Record) -> Result<()> {
         let json_schema = self
             .schemas
             .get(&record.stream)
             .ok_or_else(|| Error::JSONSchemaNotRegistered(record.stream.clone()))?;
 
-        if !json_schema.is_valid(&record.record) {
-            json_schema.validate(&record.record)?;
-        }
+        json_schema.validate(&record.record).map_err(|errors| {
+            Error::JSONSchemaValidationError(Box::new(InvalidRecord {
+                stream: record.stream.clone(),
+                record: self.record_key(record),
+                errors,
+            }))
+        })
+    }
 
-        Ok(())
+    /// Returns the key properties of the record, or the whole record when its
+    /// stream doesn't have any.
+    fn record_key(&self, record: &Record) -> Value {
+        match self.schema_messages.get(&record.stream) {
+            Some(schema) if !schema.key_properties.is_empty() => schema
+                .key_properties
+                .iter()
+                .map(|key| {
+                    (
+                        key.clone(),
+                        record.record.get(key).cloned().unwrap_or_default(),
+                    )
+                })
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+            _ => record.record.clone(),
+        }
     }
 }
 
 pub trait Target {
+    /// Describes the target for `--about`. Targets that return `None` don't
+    /// support `--about`.
+    fn about() -> Option<About>
+    where
+        Self: Sized,
+    {
+        None
+    }
+
+    /// The JSON schema the config is checked against before the target is
+    /// created, which defaults to the config schema of [Target::about].
+    fn config_schema() -> Option<Value>
+    where
+        Self: Sized,
+    {
+        Self::about().and_then(|about| about.config_schema)
+    }
+
     fn process_record(&mut self, record: Record) -> Result<()>;
 
+    /// Called with each state message. Once this returns successfully, the
+    /// state is considered confirmed and is stored in the context, so targets
+    /// that buffer records should persist them before returning.
     fn process_state(&mut self, _state: State) -> Result<()> {
         Ok(())
     }
 
+    /// Called when the tap signals that the records written with
+    /// `activate_version.version` make up the complete contents of the stream.
+    /// Targets that support versioned full table syncs should swap in the new
+    /// version and discard records from older versions.
+    fn process_activate_version(&mut self, _activate_version: ActivateVersion) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called for messages with a type this crate doesn't support. By default
+    /// the message is handled according to the context's
+    /// [UnknownMessagePolicy].
+    fn process_unknown(&mut self, context: &mut Context, ty: String, _raw: Value) -> Result<()> {
+        match context.unknown_message_policy {
+            UnknownMessagePolicy::Ignore => Ok(()),
+            UnknownMessagePolicy::Warn => {
+                eprintln!("WARNING Skipping message with unknown type {}", ty);
+                Ok(())
+            }
+            UnknownMessagePolicy::Error => Err(Error::UnknownMessageType(ty)),
+        }
+    }
+
+    /// Called with records that failed validation with
+    /// [Error::JSONSchemaValidationError]. By default the record is handled
+    /// according to the [ValidationPolicy] of its stream.
+    fn process_invalid_record(
+        &mut self,
+        context: &mut Context,
+        record: Record,
+        error: Error,
+    ) -> Result<()> {
+        match context.validation_policy(&record.stream) {
+            ValidationPolicy::Strict | ValidationPolicy::Disabled => Err(error),
+            ValidationPolicy::Skip => {
+                eprintln!("WARNING Skipping invalid record: {}", error);
+                Ok(())
+            }
+            ValidationPolicy::DeadLetter => {
+                self.process_dead_letter(context, Message::Record(record), error)
+            }
+        }
+    }
+
+    /// Called with messages that are routed to a dead letter instead of being
+    /// processed. By default the message is written to the context's
+    /// [dead_letter_sink](Context::dead_letter_sink), failing with the error
+    /// when there is none so that the message isn't lost silently.
+    fn process_dead_letter(
+        &mut self,
+        context: &mut Context,
+        message: Message,
+        error: Error,
+    ) -> Result<()> {
+        context.dead_letter(message, error)
+    }
+
+    /// Processes the messages of dead letters written by a
+    /// [DeadLetterWriter], e.g. after fixing the cause of the errors. Streams
+    /// that are already registered in the context keep their schema, rather
+    /// than going back to the schema the dead letters were written with.
+    fn replay_dead_letters<R: Read>(&mut self, context: &mut Context, reader: R) -> Result<()>
+    where
+        Self: Sized,
+    {
+        let registered: Vec<String> = context.schema_messages.keys().cloned().collect();
+        let reader = DeadLetterReader::new(reader).without_schemas(registered);
+
+        self.process_reader(context, reader)
+    }
+
+    /// Called when a stream that is already registered receives a SCHEMA
+    /// message with different properties, types or key properties, before
+    /// the new schema replaces the old one. Targets can e.g. migrate their
+    /// tables, or fail to reject the change.
+    fn on_schema_change(&mut self, _context: &mut Context, _changes: &SchemaChanges) -> Result<()> {
+        Ok(())
+    }
+
+    /// Registers the schema of the stream. When the stream is already
+    /// registered with a different schema, the changes are passed to
+    /// [Target::on_schema_change] and the validator is compiled for the new
+    /// schema.
     fn process_schema(&mut self, context: &mut Context, schema: Schema) -> Result<()> {
-        if !context.has_schema(&schema) {
-            context.insert_schema(&schema)
-        } else {
-            Ok(())
+        let changes = match context.schema_messages.get(&schema.stream) {
+            None => return context.insert_schema(&schema),
+            Some(old)
+                if old.schema == schema.schema && old.key_properties == schema.key_properties =>
+            {
+                return Ok(())
+            }
+            Some(old) => SchemaChanges::diff(old, &schema),
+        };
+
+        if !changes.is_empty() {
+            self.on_schema_change(context, &changes)?;
         }
+
+        context.insert_schema(&schema)
     }
 
     fn process_reader<R: Read>(&mut self, context: &mut Context, reader: R) -> Result<()> {
@@ -138,10 +522,37 @@ pub trait Target {
                 match message {
                     Message::Schema(schema) => self.process_schema(context, schema),
                     Message::Record(record) => {
-                        context.validate_record(&record)?;
-                        self.process_record(record)
+                        let policy = context.validation_policy(&record.stream);
+
+                        if policy != ValidationPolicy::Disabled {
+                            // only invalid records are subject to the policy, a
+                            // record without a schema is a protocol error
+                            match context.validate_record(&record) {
+                                Ok(()) => {}
+                                Err(err @ Error::JSONSchemaValidationError(_)) => {
+                                    return self.process_invalid_record(context, record, err);
+                                }
+                                Err(err) => return Err(err),
+                            }
+                        }
+
+                        if policy == ValidationPolicy::DeadLetter {
+                            let message = Message::Record(record.clone());
+                            self.process_record(record)
+                                .or_else(|err| self.process_dead_letter(context, message, err))
+                        } else {
+                            self.process_record(record)
+                        }
+                    }
+                    Message::State(state) => {
+                        self.process_state(state.clone())?;
+                        context.state = Some(state);
+                        Ok(())
+                    }
+                    Message::ActivateVersion(activate_version) => {
+                        self.process_activate_version(activate_version)
                     }
-                    Message::State(state) => self.process_state(state),
+                    Message::Unknown { ty, raw } => self.process_unknown(context, ty, raw),
                 }
             })
             .collect::<Result<Vec<()>>>()?;
@@ -155,7 +566,7 @@ mod test_target {
     use super::*;
     use crate::tap::Catalog;
 
-    static JSON_SCHEMA: &'static str = r#"{
+    static JSON_SCHEMA: &str = r#"{
   "$schema": "http://json-schema.org/schema#",
   "title": "Person",
   "type": "object",
@@ -186,7 +597,7 @@ mod test_target {
         pub fn schema() -> crate::Schema {
             crate::Schema {
                 stream: Self::stream().into(),
-                schema: serde_json::from_str(&JSON_SCHEMA).unwrap(),
+                schema: serde_json::from_str(JSON_SCHEMA).unwrap(),
                 key_properties: vec!["id".into()],
                 bookmark_properties: None,
             }
@@ -219,13 +630,15 @@ mod test_target {
     }
 
     impl crate::tap::Tap for PeopleTap {
-        fn discover(&self, _context: &mut crate::tap::Context) -> Result<Catalog> {
+        type Config = ();
+
+        fn discover(&self, _context: &mut crate::tap::Context<()>) -> Result<Catalog> {
             unimplemented!()
         }
 
         fn sync<W: std::io::Write>(
             &mut self,
-            _context: &mut crate::tap::Context,
+            _context: &mut crate::tap::Context<()>,
             writer: &mut crate::tap::MessageWriter<W>,
         ) -> Result<()> {
             writer.write_schema(Self::schema())?;
@@ -283,4 +696,379 @@ mod test_target {
 
         assert_eq!(target.people.len(), 4);
     }
+
+    #[test]
+    fn test_unknown_message_policy() {
+        use super::Target;
+
+        struct NoopTarget;
+
+        impl Target for NoopTarget {
+            fn process_record(&mut self, _record: Record) -> Result<()> {
+                Ok(())
+            }
+        }
+
+        let input = br#"{"type":"BATCH","stream":"people","manifest":[]}"#;
+
+        let mut target_ctx = super::Context::default();
+        assert!(NoopTarget
+            .process_reader(&mut target_ctx, &input[..])
+            .is_ok());
+
+        target_ctx.unknown_message_policy = UnknownMessagePolicy::Error;
+        assert!(matches!(
+            NoopTarget.process_reader(&mut target_ctx, &input[..]),
+            Err(Error::UnknownMessageType(ty)) if ty == "BATCH"
+        ));
+    }
+
+    #[test]
+    fn test_validation_errors() {
+        let mut context = super::Context::default();
+        context.insert_schema(&PeopleTap::schema()).unwrap();
+
+        let record = Record::new(
+            PeopleTap::stream(),
+            serde_json::json!({ "id": 0, "name": 5, "email": "jules@example.com" }),
+        );
+
+        let invalid = match context.validate_record(&record).unwrap_err() {
+            Error::JSONSchemaValidationError(invalid) => invalid,
+            err => panic!("unexpected error {:?}", err),
+        };
+
+        assert_eq!(invalid.stream, "people");
+        assert_eq!(invalid.record, serde_json::json!({ "id": 0 }));
+
+        let mut errors: Vec<(&str, &str, &str)> = invalid
+            .errors
+            .iter()
+            .map(|error| {
+                (
+                    error.instance_path.as_str(),
+                    error.schema_path.as_str(),
+                    error.keyword.as_str(),
+                )
+            })
+            .collect();
+        errors.sort();
+
+        assert_eq!(
+            errors,
+            vec![
+                ("/id", "/properties/id/minimum", "minimum"),
+                ("/name", "/properties/name/type", "type"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validating_on_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<JSONSchema>();
+
+        let schema = JSONSchema::new(PeopleTap::schema().schema).unwrap();
+
+        let handles: Vec<_> = PeopleTap::people()
+            .into_iter()
+            .map(|person| {
+                let schema = schema.clone();
+                std::thread::spawn(move || {
+                    schema.is_valid(&serde_json::value::to_value(person).unwrap())
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            assert!(handle.join().unwrap());
+        }
+
+        assert_eq!(schema.value()["title"], "Person");
+    }
+
+    #[test]
+    fn test_validation_policy() {
+        use super::Target;
+
+        #[derive(Default)]
+        struct CollectingTarget {
+            ids: Vec<Value>,
+            dead_letters: Vec<Message>,
+        }
+
+        impl Target for CollectingTarget {
+            fn process_record(&mut self, record: Record) -> Result<()> {
+                self.ids.push(record.record["id"].clone());
+                Ok(())
+            }
+
+            fn process_dead_letter(
+                &mut self,
+                _context: &mut super::Context,
+                message: Message,
+                _error: Error,
+            ) -> Result<()> {
+                self.dead_letters.push(message);
+                Ok(())
+            }
+        }
+
+        let input = br#"{"type":"SCHEMA","stream":"people","schema":{"type":"object","properties":{"id":{"type":"integer"}}},"key_properties":["id"]}
+{"type":"RECORD","stream":"people","record":{"id":1}}
+{"type":"RECORD","stream":"people","record":{"id":"two"}}
+{"type":"RECORD","stream":"people","record":{"id":3}}"#;
+
+        let run = |context: &mut super::Context| {
+            let mut target = CollectingTarget::default();
+            target.process_reader(context, &input[..]).map(|()| target)
+        };
+
+        let mut context = super::Context::default();
+        assert!(matches!(
+            run(&mut context),
+            Err(Error::JSONSchemaValidationError(_))
+        ));
+
+        context = super::Context {
+            validation_policy: ValidationPolicy::Skip,
+            ..Default::default()
+        };
+        let target = run(&mut context).unwrap();
+        assert_eq!(target.ids, vec![1, 3]);
+
+        context = super::Context::default();
+        context
+            .stream_validation_policies
+            .insert(String::from("people"), ValidationPolicy::DeadLetter);
+        let target = run(&mut context).unwrap();
+        assert_eq!(target.ids, vec![1, 3]);
+        assert_eq!(target.dead_letters.len(), 1);
+
+        context = super::Context {
+            validation_policy: ValidationPolicy::Disabled,
+            ..Default::default()
+        };
+        let target = run(&mut context).unwrap();
+        assert_eq!(
+            target.ids,
+            vec![Value::from(1), Value::from("two"), Value::from(3)]
+        );
+
+        // a record before its schema fails regardless of the policy
+        let input = br#"{"type":"RECORD","stream":"people","record":{"id":1}}"#;
+        for policy in [ValidationPolicy::Skip, ValidationPolicy::DeadLetter] {
+            let mut context = super::Context {
+                validation_policy: policy,
+                ..Default::default()
+            };
+            let mut target = CollectingTarget::default();
+            assert!(matches!(
+                target.process_reader(&mut context, &input[..]),
+                Err(Error::JSONSchemaNotRegistered(stream)) if stream == "people"
+            ));
+            assert!(target.dead_letters.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_dead_letters() {
+        use super::Target;
+
+        struct PickyTarget {
+            ids: Vec<Value>,
+            rejected: Value,
+        }
+
+        impl Target for PickyTarget {
+            fn process_record(&mut self, record: Record) -> Result<()> {
+                if record.record["id"] == self.rejected {
+                    return Err(Error::OtherError("rejected record"));
+                }
+                self.ids.push(record.record["id"].clone());
+                Ok(())
+            }
+        }
+
+        let input = br#"{"type":"SCHEMA","stream":"people","schema":{"type":"object","properties":{"id":{"type":"integer"}}},"key_properties":["id"]}
+{"type":"RECORD","stream":"people","record":{"id":1}}
+{"type":"RECORD","stream":"people","record":{"id":"two"}}
+{"type":"RECORD","stream":"people","record":{"id":3}}"#;
+
+        let path =
+            std::env::temp_dir().join(format!("singer-dead-letters-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut context = super::Context {
+            validation_policy: ValidationPolicy::DeadLetter,
+            ..Default::default()
+        }
+        .with_dead_letter_sink(DeadLetterWriter::append(&path).unwrap());
+
+        let mut target = PickyTarget {
+            ids: vec![],
+            rejected: Value::from(3),
+        };
+        target.process_reader(&mut context, &input[..]).unwrap();
+        assert_eq!(target.ids, vec![1]);
+
+        let dead_letters: Vec<DeadLetter> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(dead_letters.len(), 3);
+        assert!(dead_letters[0].message.is_schema());
+        assert_eq!(dead_letters[0].error, None);
+        assert_eq!(dead_letters[1].stream, "people");
+        assert_eq!(dead_letters[1].errors[0].instance_path, "/id");
+        assert_eq!(
+            dead_letters[2].error.as_deref(),
+            Some("An unexpected error occurred. rejected record")
+        );
+
+        // replay after widening the schema and fixing the target
+        let mut context = super::Context::default();
+        context
+            .insert_schema(
+                &serde_json::from_value(serde_json::json!({
+                    "stream": "people",
+                    "schema": {"type": "object", "properties": {"id": {"type": ["integer", "string"]}}},
+                    "key_properties": ["id"]
+                }))
+                .unwrap(),
+            )
+            .unwrap();
+
+        let mut target = PickyTarget {
+            ids: vec![],
+            rejected: Value::Null,
+        };
+        target
+            .replay_dead_letters(&mut context, std::fs::File::open(&path).unwrap())
+            .unwrap();
+        assert_eq!(target.ids, vec![Value::from("two"), Value::from(3)]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_schema_drafts() {
+        let schema = |url: &str| serde_json::json!({ "$schema": url });
+
+        assert_eq!(
+            detect_draft(&schema("http://json-schema.org/draft-04/schema#")),
+            Some(Draft::Draft4)
+        );
+        assert_eq!(
+            detect_draft(&schema("https://json-schema.org/draft-07/schema")),
+            Some(Draft::Draft7)
+        );
+        assert_eq!(
+            detect_draft(&schema("http://json-schema.org/schema#")),
+            None
+        );
+        assert_eq!(detect_draft(&serde_json::json!({})), None);
+
+        // `const` was added in Draft 6, so Draft 4 ignores it
+        let id = serde_json::json!({ "type": "integer", "const": 1 });
+        let schema: Schema = serde_json::from_value(serde_json::json!({
+            "stream": "people",
+            "schema": { "type": "object", "properties": { "id": id } },
+            "key_properties": ["id"]
+        }))
+        .unwrap();
+        let record = Record::new("people", serde_json::json!({ "id": 2 }));
+
+        let mut context = super::Context::default();
+        context.insert_schema(&schema).unwrap();
+        assert!(context.validate_record(&record).is_ok());
+
+        let mut context = super::Context {
+            default_draft: Some(Draft::Draft7),
+            ..Default::default()
+        };
+        context.insert_schema(&schema).unwrap();
+        assert!(context.validate_record(&record).is_err());
+    }
+
+    #[test]
+    fn test_singer_formats() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "updated_at": {
+                    "anyOf": [
+                        { "type": "null" },
+                        { "type": "string", "format": "date-time" }
+                    ]
+                },
+                "amount": { "type": ["null", "string"], "format": "singer.decimal" }
+            }
+        });
+
+        for draft in &[Draft::Draft4, Draft::Draft6, Draft::Draft7] {
+            let schema = JSONSchema::with_draft(schema.clone(), *draft).unwrap();
+
+            for updated_at in &[
+                "2021-03-04T05:06:07.123456Z",
+                "2021-03-04T05:06:07",
+                "2021-03-04 05:06:07+0000",
+                "2021-03-04",
+            ] {
+                let record = serde_json::json!({ "updated_at": updated_at, "amount": "-12.50" });
+                assert!(schema.is_valid(&record), "{:?} {}", draft, updated_at);
+            }
+
+            assert!(schema.is_valid(&serde_json::json!({ "updated_at": null, "amount": null })));
+            assert!(!schema.is_valid(&serde_json::json!({ "updated_at": "yesterday" })));
+            assert!(!schema.is_valid(&serde_json::json!({ "amount": "12,50" })));
+        }
+
+        assert!(is_singer_decimal("1e-3"));
+        assert!(is_singer_decimal(".5"));
+        assert!(!is_singer_decimal("NaN"));
+        assert!(!is_singer_decimal("1."));
+    }
+
+    #[test]
+    fn test_schema_changes() {
+        use super::Target;
+
+        #[derive(Default)]
+        struct MigratingTarget {
+            changes: Vec<SchemaChanges>,
+        }
+
+        impl Target for MigratingTarget {
+            fn process_record(&mut self, _record: Record) -> Result<()> {
+                Ok(())
+            }
+
+            fn on_schema_change(
+                &mut self,
+                _context: &mut super::Context,
+                changes: &SchemaChanges,
+            ) -> Result<()> {
+                self.changes.push(changes.clone());
+                Ok(())
+            }
+        }
+
+        let input = br#"{"type":"SCHEMA","stream":"people","schema":{"type":"object","properties":{"id":{"type":"integer"}}},"key_properties":["id"]}
+{"type":"RECORD","stream":"people","record":{"id":1}}
+{"type":"SCHEMA","stream":"people","schema":{"type":"object","properties":{"id":{"type":"integer"}}},"key_properties":["id"]}
+{"type":"SCHEMA","stream":"people","schema":{"type":"object","properties":{"id":{"type":["integer","string"]}}},"key_properties":["id"]}
+{"type":"RECORD","stream":"people","record":{"id":"two"}}"#;
+
+        let mut context = super::Context::default();
+        let mut target = MigratingTarget::default();
+        target.process_reader(&mut context, &input[..]).unwrap();
+
+        assert_eq!(target.changes.len(), 1);
+        assert!(matches!(
+            target.changes[0].changes[..],
+            [SchemaChange::TypeWidened { .. }]
+        ));
+    }
 }
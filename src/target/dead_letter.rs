@@ -0,0 +1,173 @@
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Lines, Read, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::ValidationError;
+use crate::{DateTime, Error, Message, Result, Schema};
+
+/// A message that a target couldn't process, along with why it couldn't.
+///
+/// Before the first dead letter of a stream, the stream's SCHEMA message is
+/// written as an entry without an error, so that the dead letters can be
+/// replayed on their own with
+/// [Target::replay_dead_letters](super::Target::replay_dead_letters).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub stream: String,
+    pub message: Message,
+    /// The error the message failed with, or `None` for SCHEMA entries.
+    pub error: Option<String>,
+    /// The validation errors of a record that doesn't match its schema.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<ValidationError>,
+    pub timestamp: DateTime,
+}
+
+impl DeadLetter {
+    pub fn new<S: Into<String>>(stream: S, message: Message, error: &Error) -> Self {
+        let errors = match error {
+            Error::JSONSchemaValidationError(invalid) => invalid.errors.clone(),
+            _ => vec![],
+        };
+
+        Self {
+            stream: stream.into(),
+            message,
+            error: Some(error.to_string()),
+            errors,
+            timestamp: chrono::Utc::now(),
+        }
+    }
+
+    /// The entry that precedes the first dead letter of the schema's stream.
+    pub fn schema(schema: Schema) -> Self {
+        Self {
+            stream: schema.stream.clone(),
+            message: Message::Schema(schema),
+            error: None,
+            errors: vec![],
+            timestamp: chrono::Utc::now(),
+        }
+    }
+}
+
+/// Stores dead letters, see [Context::dead_letter_sink](super::Context::dead_letter_sink).
+pub trait DeadLetterSink: Send {
+    fn write(&mut self, dead_letter: &DeadLetter) -> Result<()>;
+}
+
+/// Writes dead letters as lines of JSON.
+#[derive(Debug)]
+pub struct DeadLetterWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> DeadLetterWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl DeadLetterWriter<File> {
+    /// Appends the dead letters to the file, creating it if it doesn't exist.
+    pub fn append<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self::new(file))
+    }
+}
+
+impl<W: Write + Send> DeadLetterSink for DeadLetterWriter<W> {
+    /// Writes the dead letter and flushes it right away, since dead letters
+    /// are rare and shouldn't be lost when the target fails afterwards.
+    fn write(&mut self, dead_letter: &DeadLetter) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, dead_letter)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads the messages of the dead letters written by a [DeadLetterWriter] as
+/// lines of JSON, so that they can be passed to
+/// [Target::process_reader](super::Target::process_reader).
+pub struct DeadLetterReader<R> {
+    lines: Lines<BufReader<R>>,
+    skipped_schemas: HashSet<String>,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> DeadLetterReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            lines: BufReader::new(reader).lines(),
+            skipped_schemas: HashSet::new(),
+            buffer: vec![],
+            position: 0,
+        }
+    }
+
+    /// Skips the SCHEMA messages of the streams, e.g. to keep a schema that
+    /// was fixed since the dead letters were written.
+    pub fn without_schemas<I, S>(mut self, streams: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.skipped_schemas
+            .extend(streams.into_iter().map(Into::into));
+        self
+    }
+
+    /// Fills the buffer with the message of the next dead letter, returning
+    /// false once all dead letters have been read.
+    fn fill(&mut self) -> std::io::Result<bool> {
+        loop {
+            let line = match self.lines.next() {
+                Some(line) => line?,
+                None => return Ok(false),
+            };
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let dead_letter: DeadLetter = serde_json::from_str(&line)?;
+
+            if dead_letter.message.is_schema() && self.skipped_schemas.contains(&dead_letter.stream)
+            {
+                continue;
+            }
+
+            self.buffer.clear();
+            self.position = 0;
+            serde_json::to_writer(&mut self.buffer, &dead_letter.message)?;
+            self.buffer.push(b'\n');
+
+            return Ok(true);
+        }
+    }
+}
+
+impl<R: Read> Read for DeadLetterReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position == self.buffer.len() && !self.fill()? {
+            return Ok(0);
+        }
+
+        let read = (&self.buffer[self.position..]).read(buf)?;
+        self.position += read;
+
+        Ok(read)
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::BTreeSet;
+
+use serde_json::{Map, Value};
+
+use crate::{tap::FieldPath, Schema};
+
+/// A difference between the old and new schema of a stream, see
+/// [Target::on_schema_change](super::Target::on_schema_change).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaChange {
+    PropertyAdded(FieldPath),
+    PropertyRemoved(FieldPath),
+    /// The property accepts every type it accepted before and more, e.g. when
+    /// `null` is added or `integer` becomes `number`. A property without a
+    /// `type` accepts every type, which is represented by `None`.
+    TypeWidened {
+        path: FieldPath,
+        from: Option<BTreeSet<String>>,
+        to: Option<BTreeSet<String>>,
+    },
+    /// The property no longer accepts some of the types it accepted before.
+    TypeNarrowed {
+        path: FieldPath,
+        from: Option<BTreeSet<String>>,
+        to: Option<BTreeSet<String>>,
+    },
+    /// The property accepts different types that neither include the old
+    /// types nor are included by them, e.g. `string` becomes `integer`.
+    TypeChanged {
+        path: FieldPath,
+        from: Option<BTreeSet<String>>,
+        to: Option<BTreeSet<String>>,
+    },
+    KeyPropertiesChanged {
+        from: Vec<String>,
+        to: Vec<String>,
+    },
+}
+
+/// The changes between the old and new SCHEMA message of a stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaChanges {
+    pub stream: String,
+    pub changes: Vec<SchemaChange>,
+}
+
+impl SchemaChanges {
+    /// Compares the properties, their types and the key properties of the
+    /// schemas. Other differences, e.g. in `required` or `format`, aren't
+    /// reported.
+    pub fn diff(old: &Schema, new: &Schema) -> Self {
+        let mut changes = vec![];
+
+        diff_properties(&mut vec![], &old.schema, &new.schema, &mut changes);
+
+        if old.key_properties != new.key_properties {
+            changes.push(SchemaChange::KeyPropertiesChanged {
+                from: old.key_properties.clone(),
+                to: new.key_properties.clone(),
+            });
+        }
+
+        Self {
+            stream: new.stream.clone(),
+            changes,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+/// Compares the properties of the schemas at the path, recursing into nested
+/// objects.
+fn diff_properties(
+    path: &mut FieldPath,
+    old: &Value,
+    new: &Value,
+    changes: &mut Vec<SchemaChange>,
+) {
+    let empty = Map::new();
+    let old_properties = properties(old).unwrap_or(&empty);
+    let new_properties = properties(new).unwrap_or(&empty);
+
+    for (name, old_property) in old_properties {
+        path.push(name.clone());
+
+        match new_properties.get(name) {
+            Some(new_property) => {
+                diff_types(path, old_property, new_property, changes);
+                diff_properties(path, old_property, new_property, changes);
+            }
+            None => changes.push(SchemaChange::PropertyRemoved(path.clone())),
+        }
+
+        path.pop();
+    }
+
+    for name in new_properties.keys() {
+        if !old_properties.contains_key(name) {
+            let mut added = path.clone();
+            added.push(name.clone());
+            changes.push(SchemaChange::PropertyAdded(added));
+        }
+    }
+}
+
+fn diff_types(path: &FieldPath, old: &Value, new: &Value, changes: &mut Vec<SchemaChange>) {
+    let from = types(old);
+    let to = types(new);
+
+    if from == to {
+        return;
+    }
+
+    let path = path.clone();
+
+    let change = match (covers(&to, &from), covers(&from, &to)) {
+        (true, _) => SchemaChange::TypeWidened { path, from, to },
+        (_, true) => SchemaChange::TypeNarrowed { path, from, to },
+        _ => SchemaChange::TypeChanged { path, from, to },
+    };
+
+    changes.push(change);
+}
+
+fn properties(schema: &Value) -> Option<&Map<String, Value>> {
+    schema.get("properties")?.as_object()
+}
+
+/// The types the schema accepts, including the types of its `anyOf` and
+/// `oneOf` branches. Returns `None` when the schema accepts every type.
+fn types(schema: &Value) -> Option<BTreeSet<String>> {
+    let mut accepted = BTreeSet::new();
+
+    match schema.get("type") {
+        Some(Value::String(ty)) => {
+            accepted.insert(ty.clone());
+        }
+        Some(Value::Array(tys)) => {
+            accepted.extend(tys.iter().filter_map(Value::as_str).map(String::from));
+        }
+        _ => {}
+    }
+
+    for keyword in &["anyOf", "oneOf"] {
+        if let Some(branches) = schema.get(*keyword).and_then(Value::as_array) {
+            for branch in branches {
+                // a branch without a type accepts every type
+                accepted.extend(types(branch)?);
+            }
+        }
+    }
+
+    if accepted.is_empty() {
+        None
+    } else {
+        Some(accepted)
+    }
+}
+
+/// Whether the types `a` accept every value the types `b` accept.
+fn covers(a: &Option<BTreeSet<String>>, b: &Option<BTreeSet<String>>) -> bool {
+    match (a, b) {
+        (None, _) => true,
+        (Some(_), None) => false,
+        (Some(a), Some(b)) => b
+            .iter()
+            .all(|ty| a.contains(ty) || (ty == "integer" && a.contains("number"))),
+    }
+}
+
+#[cfg(test)]
+mod test_schema_change {
+    use super::*;
+
+    fn schema(properties: Value, key_properties: &[&str]) -> Schema {
+        serde_json::from_value(serde_json::json!({
+            "stream": "users",
+            "schema": { "type": "object", "properties": properties },
+            "key_properties": key_properties,
+        }))
+        .unwrap()
+    }
+
+    fn set(types: &[&str]) -> Option<BTreeSet<String>> {
+        Some(types.iter().map(|ty| ty.to_string()).collect())
+    }
+
+    #[test]
+    fn it_diffs_schemas() {
+        let old = schema(
+            serde_json::json!({
+                "id": { "type": "integer" },
+                "email": { "type": "string" },
+                "age": { "type": ["null", "integer"] },
+                "zip": { "type": "string" },
+                "address": {
+                    "type": "object",
+                    "properties": { "city": { "type": "string" } }
+                }
+            }),
+            &["id"],
+        );
+        let new = schema(
+            serde_json::json!({
+                "id": { "type": "integer" },
+                "age": { "type": "number" },
+                "zip": { "anyOf": [{ "type": "null" }, { "type": "string" }] },
+                "address": {
+                    "type": "object",
+                    "properties": {
+                        "city": { "type": "string" },
+                        "country": { "type": "string" }
+                    }
+                },
+                "score": { "type": "integer" }
+            }),
+            &["id", "email"],
+        );
+
+        let changes = SchemaChanges::diff(&old, &new);
+        let path = |path: &[&str]| path.iter().map(|p| p.to_string()).collect::<FieldPath>();
+
+        assert_eq!(changes.stream, "users");
+        assert_eq!(
+            changes.changes,
+            vec![
+                SchemaChange::PropertyAdded(path(&["address", "country"])),
+                SchemaChange::TypeChanged {
+                    path: path(&["age"]),
+                    from: set(&["integer", "null"]),
+                    to: set(&["number"]),
+                },
+                SchemaChange::PropertyRemoved(path(&["email"])),
+                SchemaChange::TypeWidened {
+                    path: path(&["zip"]),
+                    from: set(&["string"]),
+                    to: set(&["null", "string"]),
+                },
+                SchemaChange::PropertyAdded(path(&["score"])),
+                SchemaChange::KeyPropertiesChanged {
+                    from: vec![String::from("id")],
+                    to: vec![String::from("id"), String::from("email")],
+                },
+            ]
+        );
+
+        assert!(SchemaChanges::diff(&old, &old).is_empty());
+    }
+}